@@ -1,13 +1,47 @@
-use std::io;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json as json;
 
 use std::io::prelude::Read;
 use std::io::prelude::Write;
 
+// Makes the temporary files of concurrent writes unique.
+static NEXT_WRITE: AtomicU64 = AtomicU64::new(0);
+
+// Writes to a temporary file next to `path` and renames it over `path`, so
+// that a crash in the middle of the write never leaves a truncated file
+// behind. Concurrent writes to the same `path` each have their own temporary
+// file, the last rename wins.
 pub fn write(text: &str, path: &Path) -> io::Result<()> {
-    let mut f = File::create(path)?;
-    f.write_all(text.as_bytes())
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(
+        ".{}-{}.tmp",
+        process::id(),
+        NEXT_WRITE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = File::create(&tmp_path).and_then(|mut f| {
+        f.write_all(text.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp_path, path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
 }
 
 pub fn read(path: &Path) -> io::Result<String> {
@@ -18,3 +52,151 @@ pub fn read(path: &Path) -> io::Result<String> {
         Err(e) => Err(e),
     }
 }
+
+// A cached response along with the time (seconds since the UNIX epoch) at
+// which it was fetched.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    #[serde(rename = "FetchedAt")]
+    pub fetched_at: u64,
+    #[serde(rename = "Contents")]
+    pub contents: String,
+}
+
+impl Entry {
+    pub fn age(&self) -> Duration {
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(self.fetched_at);
+        SystemTime::now()
+            .duration_since(fetched_at)
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+// Returns the file in `directory` that holds the cache entry for `key`.
+pub fn entry_path(directory: &Path, key: &str) -> PathBuf {
+    directory.join(format!("vara_orthanc_{}.json", sanitize_key(key)))
+}
+
+// The key of the cached response of `name` to `query`. Queries are compared
+// as canonical JSON (object keys are sorted), hashed so that the key stays
+// short enough for a file name.
+pub fn query_key(name: &str, query: &json::Value) -> String {
+    // FNV-1a, which unlike `DefaultHasher` is the same across Rust versions,
+    // so that entries written before an upgrade are still found.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in query.to_string().bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{}_{:016x}", name, hash)
+}
+
+// Makes `key` usable as (part of) a file name, e.g. when `key` is an URL.
+pub fn sanitize_key(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn write_entry(directory: &Path, key: &str, contents: &str) -> io::Result<()> {
+    let fetched_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let entry = Entry {
+        fetched_at,
+        contents: contents.to_string(),
+    };
+    fs::create_dir_all(directory)?;
+    write(&json::to_string(&entry)?, &entry_path(directory, key))
+}
+
+pub fn read_entry(directory: &Path, key: &str) -> io::Result<Entry> {
+    let text = read(&entry_path(directory, key))?;
+    Ok(json::from_str(&text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "vara_orthanc_cache_test_{}_{}",
+            name,
+            process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn sanitizes_keys() {
+        assert_eq!(sanitize_key("ris-a_1"), "ris-a_1");
+        assert_eq!(
+            sanitize_key("http://ris:8042/find-worklist?x=1"),
+            "http___ris_8042_find-worklist_x_1"
+        );
+        assert_eq!(sanitize_key("../étc"), "____tc");
+    }
+
+    #[test]
+    fn keys_queries_canonically() {
+        let query = json!({"0008,0060": "CT", "0040,0002": "20231018"});
+        let same_query = json!({"0040,0002": "20231018", "0008,0060": "CT"});
+        let other_query = json!({"0008,0060": "MR", "0040,0002": "20231018"});
+        assert_eq!(query_key("ris", &query), query_key("ris", &same_query));
+        assert_ne!(query_key("ris", &query), query_key("ris", &other_query));
+        assert_ne!(query_key("ris", &query), query_key("pacs", &query));
+        assert!(query_key("ris", &query).starts_with("ris_"));
+    }
+
+    #[test]
+    fn reads_written_entries() {
+        let directory = test_directory("entries");
+        write_entry(&directory, "ris/a", "[{\"0010,0020\": \"1\"}]").unwrap();
+        let entry = read_entry(&directory, "ris/a").unwrap();
+        assert_eq!(entry.contents, "[{\"0010,0020\": \"1\"}]");
+        assert!(entry.age() < Duration::from_secs(60));
+
+        write_entry(&directory, "ris/a", "[]").unwrap();
+        assert_eq!(read_entry(&directory, "ris/a").unwrap().contents, "[]");
+        assert!(read_entry(&directory, "ris/b").is_err());
+
+        // No temporary file is left behind.
+        let files: Vec<PathBuf> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files, vec![entry_path(&directory, "ris/a")]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn writes_concurrently() {
+        let directory = test_directory("concurrent");
+        let writers: Vec<_> = (0..8)
+            .map(|index| {
+                let directory = directory.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        write_entry(&directory, "ris", &index.to_string()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let contents = read_entry(&directory, "ris").unwrap().contents;
+        assert!(contents.parse::<u32>().unwrap() < 8);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
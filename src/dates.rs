@@ -0,0 +1,59 @@
+// UTC dates and times in the formats used by DICOM and HL7, without pulling
+// in a date crate, and the local time of day of transfer windows.
+
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+// `YYYYMMDDHHMMSS` of `seconds` since the UNIX epoch.
+pub fn timestamp(seconds: u64) -> String {
+    let seconds_of_day = seconds % 86400;
+    format!(
+        "{}{:02}{:02}{:02}",
+        date(seconds),
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+// `YYYYMMDD` (a DICOM DA) of `seconds` since the UNIX epoch.
+pub fn date(seconds: u64) -> String {
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (seconds / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+// `YYYYMMDD` of the day `days` days before today.
+pub fn date_days_ago(days: u64) -> String {
+    date(now().saturating_sub(days * 86400))
+}
+
+// Minutes since midnight, local time, at `seconds` since the UNIX epoch.
+pub fn local_minute_of_day(seconds: u64) -> u64 {
+    let time = seconds as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        // UTC if the local time is unknown.
+        return seconds % 86400 / 60;
+    }
+    tm.tm_hour as u64 * 60 + tm.tm_min as u64
+}
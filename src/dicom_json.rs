@@ -0,0 +1,369 @@
+// Conversion between the standard DICOM JSON model (PS3.18 Annex F, as used by
+// DICOMweb) and the simplified format accepted by Orthanc's `CreateDicom`
+// service (and returned by its "Short" JSON format), which is the only format
+// the Orthanc core parser accepts:
+//
+//   {"00100010": {"vr": "PN", "Value": [{"Alphabetic": "DOE^JOHN"}]},
+//    "00281050": {"vr": "DS", "Value": [40, 400]},
+//    "00400100": {"vr": "SQ", "Value": [{"00080060": {"vr": "CS", "Value": ["CT"]}}]}}
+//
+//   {"0010,0010": "DOE^JOHN",
+//    "0028,1050": "40\\400",
+//    "0040,0100": [{"0008,0060": "CT"}]}
+//
+// Multiple values are joined with backslashes, numbers are formatted as
+// strings, person name groups are joined with `=` and inline binary values
+// become `data:` URIs (decoded by `CreateDicom` with the
+// `DecodeDataUriScheme` flag). Bulk data URIs cannot be resolved and are
+// dropped.
+//
+// The simplified format has no value representations, so converting back to
+// DICOM JSON requires the VR of every tag, e.g. as collected from the original
+// dataset with `value_representations`.
+
+use serde_json::Map as JsonMap;
+use serde_json::Number as JsonNumber;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::error::Error;
+
+const DATA_URI_PREFIX: &str = "data:application/octet-stream;base64,";
+
+pub fn from_dicomweb(dataset: &JsonValue) -> Result<JsonValue, Box<dyn Error>> {
+    let dataset = dataset
+        .as_object()
+        .ok_or("Expected a DICOM JSON dataset (JSON object)")?;
+
+    let mut converted = JsonMap::new();
+    for (tag, element) in dataset {
+        let orthanc_tag = orthanc_tag(tag)?;
+        let vr = element["vr"].as_str().unwrap_or("UN");
+        if element.get("BulkDataURI").is_some() {
+            continue;
+        }
+        if let Some(inline_binary) = element["InlineBinary"].as_str() {
+            converted.insert(
+                orthanc_tag,
+                JsonValue::String(format!("{}{}", DATA_URI_PREFIX, inline_binary)),
+            );
+            continue;
+        }
+
+        let values = match &element["Value"] {
+            JsonValue::Array(values) => values.as_slice(),
+            JsonValue::Null => &[],
+            _ => return Err(format!("Value of {} is not an array", tag).into()),
+        };
+        let value = if vr == "SQ" {
+            JsonValue::Array(
+                values
+                    .iter()
+                    .map(from_dicomweb)
+                    .collect::<Result<Vec<JsonValue>, Box<dyn Error>>>()?,
+            )
+        } else {
+            let values = values
+                .iter()
+                .map(|value| string_value(vr, value))
+                .collect::<Result<Vec<String>, Box<dyn Error>>>()
+                .map_err(|error| format!("Invalid value of {}: {}", tag, error))?;
+            JsonValue::String(values.join("\\"))
+        };
+        converted.insert(orthanc_tag, value);
+    }
+    Ok(JsonValue::Object(converted))
+}
+
+pub fn to_dicomweb(
+    dataset: &JsonValue,
+    vrs: &HashMap<String, String>,
+) -> Result<JsonValue, Box<dyn Error>> {
+    let dataset = dataset
+        .as_object()
+        .ok_or("Expected an Orthanc JSON dataset (JSON object)")?;
+
+    let mut converted = JsonMap::new();
+    for (tag, value) in dataset {
+        let dicomweb_tag = dicomweb_tag(tag)?;
+        // Sequences are the only values that can be told apart without the VR.
+        let default_vr = if value.is_array() { "SQ" } else { "UN" };
+        let vr = vrs
+            .get(&dicomweb_tag)
+            .map(String::as_str)
+            .unwrap_or(default_vr);
+
+        let mut element = JsonMap::new();
+        element.insert(String::from("vr"), JsonValue::String(vr.to_string()));
+        match value {
+            JsonValue::Array(items) => {
+                if !items.is_empty() {
+                    let items = items
+                        .iter()
+                        .map(|item| to_dicomweb(item, vrs))
+                        .collect::<Result<Vec<JsonValue>, Box<dyn Error>>>()?;
+                    element.insert(String::from("Value"), JsonValue::Array(items));
+                }
+            }
+            JsonValue::String(value) => {
+                if let Some(inline_binary) = value.strip_prefix(DATA_URI_PREFIX) {
+                    element.insert(
+                        String::from("InlineBinary"),
+                        JsonValue::String(inline_binary.to_string()),
+                    );
+                } else if !value.is_empty() {
+                    let values = value
+                        .split('\\')
+                        .map(|value| json_value(vr, value))
+                        .collect::<Result<Vec<JsonValue>, Box<dyn Error>>>()
+                        .map_err(|error| format!("Invalid value of {}: {}", tag, error))?;
+                    element.insert(String::from("Value"), JsonValue::Array(values));
+                }
+            }
+            JsonValue::Null => (),
+            _ => return Err(format!("Unexpected value of {}: {}", tag, value).into()),
+        }
+        converted.insert(dicomweb_tag, JsonValue::Object(element));
+    }
+    Ok(JsonValue::Object(converted))
+}
+
+// Returns the VR of every tag of a DICOM JSON dataset, including the tags of
+// nested sequences, keyed by DICOM JSON tag (e.g. `"00100010"`).
+pub fn value_representations(dataset: &JsonValue) -> HashMap<String, String> {
+    let mut vrs = HashMap::new();
+    collect_value_representations(dataset, &mut vrs);
+    vrs
+}
+
+fn collect_value_representations(dataset: &JsonValue, vrs: &mut HashMap<String, String>) {
+    for (tag, element) in dataset.as_object().into_iter().flatten() {
+        if let Some(vr) = element["vr"].as_str() {
+            vrs.insert(tag.to_ascii_uppercase(), vr.to_string());
+        }
+        for item in element["Value"].as_array().into_iter().flatten() {
+            if item.is_object() && element["vr"] == "SQ" {
+                collect_value_representations(item, vrs);
+            }
+        }
+    }
+}
+
+// `"0010001A"` -> `"0010,001A"`
+fn orthanc_tag(tag: &str) -> Result<String, Box<dyn Error>> {
+    if tag.len() != 8 || !tag.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid DICOM JSON tag: {}", tag).into());
+    }
+    let tag = tag.to_ascii_uppercase();
+    Ok(format!("{},{}", &tag[..4], &tag[4..]))
+}
+
+// `"0010,001a"` -> `"0010001A"`
+fn dicomweb_tag(tag: &str) -> Result<String, Box<dyn Error>> {
+    match tag.split_once(',') {
+        Some((group, element))
+            if group.len() == 4
+                && element.len() == 4
+                && group
+                    .chars()
+                    .chain(element.chars())
+                    .all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok(format!("{}{}", group, element).to_ascii_uppercase())
+        }
+        _ => Err(format!("Invalid Orthanc tag: {}", tag).into()),
+    }
+}
+
+fn string_value(vr: &str, value: &JsonValue) -> Result<String, Box<dyn Error>> {
+    match value {
+        JsonValue::Null => Ok(String::new()),
+        JsonValue::String(value) => Ok(value.clone()),
+        JsonValue::Number(value) => Ok(value.to_string()),
+        JsonValue::Object(name) if vr == "PN" => {
+            let group = |key: &str| name.get(key).and_then(JsonValue::as_str).unwrap_or("");
+            let name = [group("Alphabetic"), group("Ideographic"), group("Phonetic")].join("=");
+            Ok(name.trim_end_matches('=').to_string())
+        }
+        _ => Err(format!("Unexpected {} value: {}", vr, value).into()),
+    }
+}
+
+fn json_value(vr: &str, value: &str) -> Result<JsonValue, Box<dyn Error>> {
+    if value.is_empty() {
+        return Ok(JsonValue::Null);
+    }
+    match vr {
+        "PN" => {
+            let mut groups = value.split('=');
+            let mut name = JsonMap::new();
+            for key in ["Alphabetic", "Ideographic", "Phonetic"] {
+                match groups.next() {
+                    Some(group) if !group.is_empty() => {
+                        name.insert(key.to_string(), JsonValue::String(group.to_string()));
+                    }
+                    _ => (),
+                }
+            }
+            Ok(JsonValue::Object(name))
+        }
+        "IS" | "SL" | "SS" | "SV" | "UL" | "US" | "UV" => {
+            let value = value.trim();
+            Ok(match value.parse::<i64>() {
+                Ok(value) => JsonValue::from(value),
+                Err(_) => JsonValue::from(value.parse::<u64>()?),
+            })
+        }
+        "DS" | "FL" | "FD" => {
+            let value = value.trim();
+            match value.parse::<i64>() {
+                Ok(value) => Ok(JsonValue::from(value)),
+                Err(_) => JsonNumber::from_f64(value.parse::<f64>()?)
+                    .map(JsonValue::Number)
+                    .ok_or_else(|| format!("{} cannot be represented in JSON", value).into()),
+            }
+        }
+        _ => Ok(JsonValue::String(value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn assert_round_trip(dicomweb: JsonValue) {
+        let orthanc = from_dicomweb(&dicomweb).unwrap();
+        let vrs = value_representations(&dicomweb);
+        assert_eq!(to_dicomweb(&orthanc, &vrs).unwrap(), dicomweb);
+    }
+
+    #[test]
+    fn converts_to_orthanc_format() {
+        let dicomweb = json!({
+            "00080005": {"vr": "CS", "Value": ["ISO_IR 192"]},
+            "00080050": {"vr": "SH", "Value": ["A1234"]},
+            "00100010": {"vr": "PN", "Value": [{"Alphabetic": "DOE^JOHN"}]},
+            "00100020": {"vr": "LO"},
+            "00281050": {"vr": "DS", "Value": [40, 400.5]},
+            "00400100": {"vr": "SQ", "Value": [
+                {"00080060": {"vr": "CS", "Value": ["CT"]},
+                 "00400002": {"vr": "DA", "Value": ["20231018"]}}
+            ]}
+        });
+
+        assert_eq!(
+            from_dicomweb(&dicomweb).unwrap(),
+            json!({
+                "0008,0005": "ISO_IR 192",
+                "0008,0050": "A1234",
+                "0010,0010": "DOE^JOHN",
+                "0010,0020": "",
+                "0028,1050": "40\\400.5",
+                "0040,0100": [{"0008,0060": "CT", "0040,0002": "20231018"}]
+            })
+        );
+    }
+
+    #[test]
+    fn converts_binary_values_to_data_uris() {
+        let dicomweb = json!({
+            "00091001": {"vr": "OB", "InlineBinary": "AAECAw=="},
+            "7FE00010": {"vr": "OW", "BulkDataURI": "http://pacs/bulk/1"}
+        });
+
+        assert_eq!(
+            from_dicomweb(&dicomweb).unwrap(),
+            json!({"0009,1001": "data:application/octet-stream;base64,AAECAw=="})
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_datasets() {
+        assert!(from_dicomweb(&json!([])).is_err());
+        assert!(from_dicomweb(&json!({"0010": {"vr": "LO", "Value": ["x"]}})).is_err());
+        assert!(from_dicomweb(&json!({"00100020": {"vr": "LO", "Value": "x"}})).is_err());
+        assert!(from_dicomweb(&json!({"00100020": {"vr": "LO", "Value": [{}]}})).is_err());
+        assert!(to_dicomweb(&json!({"0010,00200": "x"}), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn round_trips_string_vrs() {
+        assert_round_trip(json!({
+            "00080020": {"vr": "DA", "Value": ["20231018"]},
+            "00080030": {"vr": "TM", "Value": ["143000.000"]},
+            "0008002A": {"vr": "DT", "Value": ["20231018143000.000+0100"]},
+            "00080060": {"vr": "CS", "Value": ["CT"]},
+            "00080050": {"vr": "SH", "Value": ["A1234"]},
+            "00081030": {"vr": "LO", "Value": ["CT HEAD"]},
+            "00101010": {"vr": "AS", "Value": ["043Y"]},
+            "00104000": {"vr": "LT", "Value": ["Patient comments"]},
+            "0020000D": {"vr": "UI", "Value": ["1.2.840.113619.2.1"]},
+            "00209165": {"vr": "AT", "Value": ["00100010"]},
+            "00400254": {"vr": "LO"}
+        }));
+    }
+
+    #[test]
+    fn round_trips_numeric_vrs() {
+        assert_round_trip(json!({
+            "00200013": {"vr": "IS", "Value": [7]},
+            "00281050": {"vr": "DS", "Value": [40, -400.25]},
+            "00280010": {"vr": "US", "Value": [512]},
+            "00189219": {"vr": "SS", "Value": [-12]},
+            "00280106": {"vr": "UL", "Value": [4294967295u64]},
+            "00186020": {"vr": "SL", "Value": [-2147483648i64]},
+            "00189087": {"vr": "FD", "Value": [1000.5]},
+            "00189218": {"vr": "FL", "Value": [0.25]}
+        }));
+    }
+
+    #[test]
+    fn round_trips_multiple_and_empty_values() {
+        assert_round_trip(json!({
+            "00080008": {"vr": "CS", "Value": ["ORIGINAL", "PRIMARY", null, "AXIAL"]},
+            "00200037": {"vr": "DS", "Value": [1, 0, 0, 0, 1, 0]}
+        }));
+    }
+
+    #[test]
+    fn round_trips_person_names() {
+        assert_round_trip(json!({
+            "00100010": {"vr": "PN", "Value": [{
+                "Alphabetic": "Yamada^Tarou",
+                "Ideographic": "山田^太郎",
+                "Phonetic": "やまだ^たろう"
+            }]},
+            "00080090": {"vr": "PN", "Value": [{"Alphabetic": "SMITH^ANNA"}, {"Alphabetic": "JONES^BOB"}]},
+            "00081060": {"vr": "PN", "Value": [{"Ideographic": "山田^太郎"}]}
+        }));
+    }
+
+    #[test]
+    fn round_trips_binary_vrs() {
+        assert_round_trip(json!({
+            "00091001": {"vr": "OB", "InlineBinary": "AAECAw=="},
+            "00091002": {"vr": "OW", "InlineBinary": "AAEAAg=="},
+            "00091003": {"vr": "UN", "InlineBinary": "/w=="}
+        }));
+    }
+
+    #[test]
+    fn round_trips_nested_sequences() {
+        assert_round_trip(json!({
+            "00400100": {"vr": "SQ", "Value": [
+                {
+                    "00080060": {"vr": "CS", "Value": ["MR"]},
+                    "00400008": {"vr": "SQ", "Value": [
+                        {
+                            "00080100": {"vr": "SH", "Value": ["MRKNEE"]},
+                            "00080102": {"vr": "SH", "Value": ["99LOCAL"]},
+                            "00080104": {"vr": "LO", "Value": ["MR Knee"]}
+                        }
+                    ]}
+                },
+                {"00080060": {"vr": "CS", "Value": ["CT"]}}
+            ]},
+            "00321064": {"vr": "SQ"}
+        }));
+    }
+}
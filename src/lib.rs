@@ -7,7 +7,10 @@ extern crate serde_json;
 extern crate tracing;
 
 pub mod cache;
+pub mod dates;
+pub mod dicom_json;
 pub mod orthanc;
+pub mod worklist;
 
 use orthanc::plugin;
 use orthanc::plugin::OrthancPluginContext;
@@ -16,13 +19,15 @@ use orthanc::plugin::OrthancPluginErrorCode_OrthancPluginErrorCode_Success as Or
 use orthanc::plugin::OrthancPluginMemoryBuffer;
 use orthanc::plugin::OrthancPluginWorklistAnswers;
 use orthanc::plugin::OrthancPluginWorklistQuery;
+use orthanc::plugin::WorklistEndpoint;
+use orthanc::plugin::WorklistEndpointType;
 
+use serde::Serialize;
 use serde_json::Value as JsonValue;
 
 use libc::{c_char, c_void};
 use std::env;
 use std::ffi::CString;
-use std::path::Path;
 use std::vec::Vec;
 
 use std::{thread, time};
@@ -41,6 +46,38 @@ pub extern "C" fn OrthancPluginInitialize(context: *mut OrthancPluginContext) ->
 
     register_on_worklist_callback(Some(on_worklist_callback));
     register_on_change_callback(Some(on_change));
+    register_rest_callback("/vara/sync/reconcile", Some(on_reconcile_request));
+    register_rest_callback("/vara/transfers/jobs", Some(on_transfer_jobs_request));
+    register_rest_callback("/vara/transfers/retries", Some(on_retries_request));
+    register_rest_callback(
+        "/vara/transfers/dead-letters",
+        Some(on_dead_letters_request),
+    );
+    register_rest_callback(
+        "/vara/transfers/dead-letters/replay",
+        Some(on_replay_dead_letters_request),
+    );
+    register_rest_callback(
+        "/vara/transfers/verification-failures",
+        Some(on_verification_failures_request),
+    );
+    register_rest_callback("/vara/prefetch", Some(on_prefetch_request));
+    worklist::hl7::start_listeners();
+    orthanc::jobs::start_poller();
+    orthanc::retries::load();
+    orthanc::retries::start_worker();
+    orthanc::retention::start_worker();
+    orthanc::pull::start_worker();
+    orthanc::prefetch::start_worker();
+    if orthanc::plugin::get_sync_lookback_days().is_some()
+        && orthanc::plugin::get_full_reconcile_interval().is_none()
+    {
+        // New peers start from the latest change, see `orthanc::sync_instances`.
+        orthanc::plugin::warning(
+            "\"VaraProxy\" -> \"Sync\" -> \"LookbackDays\" is set without \"FullReconcileIntervalHours\": \
+             studies older than the lookback are never sent to new peers",
+        );
+    }
     // Spin off a thread for creating jobs to synchronize existing studies.
     orthanc::plugin::get_threadpool().execute(move || {
         // If plugin initialization takes more than 60 seconds, it's fine to
@@ -55,6 +92,10 @@ pub extern "C" fn OrthancPluginInitialize(context: *mut OrthancPluginContext) ->
             if let Err(error) = orthanc::sync_instances() {
                 orthanc::plugin::error(&format!("Periodic sync failed. {:?}", error));
             }
+            // Every "VaraProxy" -> "Sync" -> "FullReconcileIntervalHours".
+            if let Err(error) = orthanc::reconcile_if_due() {
+                orthanc::plugin::error(&format!("Full reconcile failed. {:?}", error));
+            }
             orthanc::plugin::info("[Periodic Sync] End.");
             thread::sleep(time::Duration::from_secs(orthanc::plugin::get_sync_interval()));
         }
@@ -81,33 +122,137 @@ pub extern "C" fn OrthancPluginGetVersion() -> *const u8 {
 extern "C" fn on_worklist_callback(
     answers: *mut OrthancPluginWorklistAnswers,
     query: *const OrthancPluginWorklistQuery,
-    _issuerAet: *const c_char,
-    _calledAet: *const c_char,
+    issuerAet: *const c_char,
+    calledAet: *const c_char,
 ) -> OrthancPluginErrorCode {
-    let mwl_endpoints = orthanc_modality_endpoints();
-    for endpoint in &mwl_endpoints {
-        let worklist_items: Vec<JsonValue> = match orthanc_modality_worklist(endpoint) {
-            Ok(JsonValue::Array(v)) => v,
-            _ => {
-                orthanc::plugin::error("Failed to fetch modality worklist from peer Orthanc");
-                return 1;
+    let issuer_aet = c_str_or_empty(issuerAet);
+    let called_aet = c_str_or_empty(calledAet);
+    let endpoints = orthanc_modality_endpoints();
+    let route = match worklist::routing::route(&issuer_aet, &called_aet, endpoints) {
+        Some(route) => route,
+        None => {
+            orthanc::plugin::info(&format!(
+                "Denied C-FIND worklist request from {} to {}: no matching routing rule",
+                issuer_aet, called_aet
+            ));
+            return OrthancCodeSuccess;
+        }
+    };
+
+    let worklist_query = match worklist_query_json(query) {
+        Ok(worklist_query) => worklist_query,
+        Err(error) => {
+            orthanc::plugin::error(&format!(
+                "Failed to read the C-FIND worklist query: {:?}",
+                error
+            ));
+            return 1;
+        }
+    };
+
+    // A failing endpoint must not prevent the other endpoints from answering.
+    // Its failure is only reported to the modality by marking the answers as
+    // incomplete.
+    let mwl_endpoints = &route.endpoints;
+    // Every item comes with whether its binary values are `data:` URIs, which
+    // is only the case of DICOM JSON converted by `dicom_json::from_dicomweb`.
+    let mut worklists = Vec::with_capacity(mwl_endpoints.len());
+    let mut incomplete = false;
+    for endpoint in mwl_endpoints {
+        let worklist = match endpoint.kind {
+            WorklistEndpointType::Orthanc => orthanc_modality_worklist(endpoint, &worklist_query),
+            // "Path" is checked by `get_worklist_endpoints`.
+            WorklistEndpointType::Folder | WorklistEndpointType::Hl7 => {
+                worklist::folder::read_worklist(endpoint.path.as_ref().unwrap())
             }
+            WorklistEndpointType::Fhir => cached_worklist(
+                endpoint,
+                &worklist_query,
+                worklist::fhir::fetch_worklist(endpoint, &worklist_query)
+                    .map(|items| JsonValue::Array(items).to_string()),
+            ),
+            WorklistEndpointType::UpsRs => cached_worklist(
+                endpoint,
+                &worklist_query,
+                worklist::ups::fetch_worklist(endpoint, &worklist_query)
+                    .map(|items| JsonValue::Array(items).to_string()),
+            ),
         };
-        for item in worklist_items {
-            let mut buffer = memory_buffer();
-            let buffer_ptr = &mut buffer as *mut OrthancPluginMemoryBuffer;
-            create_dicom(item.to_string(), buffer_ptr);
-            if dicom_matches_query(query, buffer_ptr) {
-                add_worklist_query_answer(answers, query, buffer_ptr)
-            };
-            orthanc::plugin::free_buffer(buffer_ptr);
+        match worklist {
+            Ok(JsonValue::Array(v)) => {
+                let data_uris = endpoint.kind == WorklistEndpointType::UpsRs;
+                worklists.push(v.into_iter().map(|item| (item, data_uris)).collect())
+            }
+            Ok(response) => {
+                orthanc::plugin::error(&format!(
+                    "Failed to fetch modality worklist from {}. Expected a JSON array, got: {}",
+                    endpoint.name, response
+                ));
+                incomplete = true;
+            }
+            Err(error) => {
+                orthanc::plugin::error(&format!(
+                    "Failed to fetch modality worklist from {}. {:?}",
+                    endpoint.name, error
+                ));
+                incomplete = true;
+            }
+        };
+    }
+    if worklists.is_empty() && !mwl_endpoints.is_empty() {
+        orthanc::plugin::error("Failed to fetch modality worklist from all endpoints");
+        return 1;
+    }
+
+    let mut patient_ids = vec![];
+    for (item, data_uris) in worklist::merge_worklist_items(worklists, |(item, _)| item) {
+        if !route.accepts(&item) {
+            continue;
         }
+        let mut buffer = memory_buffer();
+        let buffer_ptr = &mut buffer as *mut OrthancPluginMemoryBuffer;
+        create_dicom(item.to_string(), data_uris, buffer_ptr);
+        if dicom_matches_query(query, buffer_ptr) {
+            add_worklist_query_answer(answers, query, buffer_ptr);
+            if let Some(patient_id) = worklist::get_patient_id(&item) {
+                patient_ids.push(patient_id.to_string());
+            }
+        };
+        orthanc::plugin::free_buffer(buffer_ptr);
+    }
+    // Their studies can be pulled from the peer, see `orthanc::pull` and
+    // `orthanc::prefetch`.
+    orthanc::pull::remember_worklist_patients(patient_ids.clone());
+    orthanc::prefetch::request(patient_ids);
+    if incomplete {
+        mark_worklist_answers_incomplete(answers);
     }
     return OrthancCodeSuccess;
 }
 
-fn orthanc_modality_worklist(endpoint: &str) -> Result<JsonValue, Box<dyn std::error::Error>> {
-    let http_client = reqwest::blocking::Client::new();
+fn c_str_or_empty(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    unsafe { std::ffi::CStr::from_ptr(s) }
+        .to_string_lossy()
+        .to_string()
+}
+
+fn orthanc_modality_worklist(
+    endpoint: &WorklistEndpoint,
+    worklist_query: &JsonValue,
+) -> Result<JsonValue, Box<dyn std::error::Error>> {
+    #[derive(Serialize, Debug)]
+    struct FindWorklistRequest<'a> {
+        #[serde(rename = "Short")]
+        short: bool,
+        #[serde(rename = "Query")]
+        query: &'a JsonValue,
+    }
+
+    let http_client = plugin::get_http_client();
+
     //
     //  Sample JSON payload that works:
     // {
@@ -126,54 +271,77 @@ fn orthanc_modality_worklist(endpoint: &str) -> Result<JsonValue, Box<dyn std::e
     //
     // Note that
     // https://dicom.nema.org/dicom/2013/output/chtml/part18/sect_F.2.html is
-    // considered invalid JSON by the Orthanc core parser.
-    let orthanc_api_user = env::var("VARA_ORTHANC_API_USER").unwrap_or(String::from("admin"));
-    let orthanc_api_password =
-        env::var("VARA_ORTHANC_API_PASSWORD").unwrap_or(String::from("password"));
-
-    let workitems = http_client
-        .post(endpoint)
-        .body(
-            r#"{"Short": true,
-                "Query": {"AccessionNumber": "*",
-                          "StudyID": null,
-                          "StudyInstanceUID": null,
-                          "StudyDescription": null,
-
-                          "ReferringPhysicianName": null,
-                          "ReferringPhysician": null,
-
-                          "PatientID": null,
-                          "PatientName": null,
-                          "PatientBirthDate": null,
-                          "PatientSex": null,
-
-                          "RequestedProcedureID": null,
-                          "RequestedProcedureDescription": null,
-                          "RequestedProcedureCodeSequence": [],
-                          "ScheduledProcedureStepSequence": []}}"#,
-        )
-        .basic_auth(orthanc_api_user, Some(orthanc_api_password))
-        .send();
+    // considered invalid JSON by the Orthanc core parser, sources returning
+    // DICOM JSON convert it with `dicom_json::from_dicomweb`.
+    //
+    // Only the keys sent by the modality are forwarded so that the upstream
+    // does the matching instead of returning its entire worklist. Answers are
+    // still checked locally with `dicom_matches_query` before being returned.
+    let request = http_client.post(&endpoint.url).json(&FindWorklistRequest {
+        short: true,
+        query: worklist_query,
+    });
+    let workitems = worklist::authorize(endpoint, request)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text());
+
+    cached_worklist(
+        endpoint,
+        worklist_query,
+        workitems.map_err(|error| error.into()),
+    )
+}
 
-    let cache_file = Path::new("vara_orthanc.json");
-    let json_response = if workitems.is_err() || !workitems.as_ref().unwrap().status().is_success()
-    {
-        orthanc::plugin::info(&format!(
-            "Reading the cache file for MWL entries. Failure: {:?}",
-            workitems
-        ));
-        match cache::read(&cache_file) {
-            Ok(contents) => contents,
-            Err(error) => {
-                orthanc::plugin::warning("Failed to read cache file");
-                return Err(Box::new(error));
+// The last successful response of every endpoint to every query is cached so
+// that modalities still get answers while the endpoint is unreachable. Entries
+// are keyed by endpoint and query (see `cache::query_key`), so that a modality
+// never gets the answers to another query.
+fn cached_worklist(
+    endpoint: &WorklistEndpoint,
+    worklist_query: &JsonValue,
+    response: Result<String, Box<dyn std::error::Error>>,
+) -> Result<JsonValue, Box<dyn std::error::Error>> {
+    let cache_directory = orthanc::plugin::get_worklist_cache_directory();
+    let cache_key = cache::query_key(&endpoint.name, worklist_query);
+    let json_response = match response {
+        Err(error) => {
+            orthanc::plugin::info(&format!(
+                "Reading the cache file for MWL entries of {}. Failure: {:?}",
+                endpoint.name, error
+            ));
+            let entry = match cache::read_entry(&cache_directory, &cache_key) {
+                Ok(entry) => entry,
+                Err(error) => {
+                    orthanc::plugin::warning(&format!(
+                        "Failed to read cache file of {}",
+                        endpoint.name
+                    ));
+                    return Err(Box::new(error));
+                }
+            };
+            match orthanc::plugin::get_worklist_max_staleness() {
+                Some(max_staleness) if entry.age() > max_staleness => {
+                    return Err(format!(
+                        "Cached MWL entries of {} are {} seconds old, which exceeds MaxStalenessSeconds ({})",
+                        endpoint.name,
+                        entry.age().as_secs(),
+                        max_staleness.as_secs()
+                    )
+                    .into());
+                }
+                _ => entry.contents,
             }
         }
-    } else {
-        let response = workitems?.text().unwrap();
-        cache::write(&response, &cache_file)?;
-        response
+        Ok(response) => {
+            if let Err(error) = cache::write_entry(&cache_directory, &cache_key, &response) {
+                orthanc::plugin::warning(&format!(
+                    "Failed to write cache file of {}: {:?}",
+                    endpoint.name, error
+                ));
+            }
+            response
+        }
     };
 
     Ok(serde_json::from_str(&json_response)?)
@@ -195,29 +363,187 @@ fn register_on_change_callback(callback: orthanc::plugin::OrthancPluginOnChangeC
     );
 }
 
-// Returns a vector of endpoint URLs that can be queried for getting modality
-// worklist items. Currently only supports a single endpoint that is configured
-// by setting by environment variable: `VARA_ORTHANC_MODALITY_ENDPOINT`.
+fn register_rest_callback(
+    path_regular_expression: &str,
+    callback: orthanc::plugin::OrthancPluginRestCallback,
+) {
+    let path_regular_expression = CString::new(path_regular_expression).unwrap();
+    let params = orthanc::plugin::_OrthancPluginRestCallback {
+        pathRegularExpression: path_regular_expression.as_ptr(),
+        callback,
+    };
+    orthanc::plugin::invoke_orthanc_service(
+        orthanc::plugin::_OrthancPluginService__OrthancPluginService_RegisterRestCallback,
+        &params as *const orthanc::plugin::_OrthancPluginRestCallback as *mut c_void,
+    );
+}
+
+// `POST /vara/sync/reconcile` starts a full reconcile between the local
+// Orthanc and the peer (see `orthanc::reconcile_instances`) in the background.
+extern "C" fn on_reconcile_request(
+    output: *mut orthanc::plugin::OrthancPluginRestOutput,
+    _url: *const c_char,
+    request: *const orthanc::plugin::OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let method = unsafe { (*request).method };
+    if method != orthanc::plugin::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Post {
+        orthanc::plugin::send_method_not_allowed(output, "POST");
+        return OrthancCodeSuccess;
+    }
+
+    orthanc::plugin::get_threadpool().execute(move || {
+        orthanc::plugin::info("[Full Reconcile] Begin.");
+        if let Err(error) = orthanc::reconcile_instances() {
+            orthanc::plugin::error(&format!("Full reconcile failed. {:?}", error));
+        }
+        orthanc::plugin::info("[Full Reconcile] End.");
+    });
+    orthanc::plugin::answer_json(output, &serde_json::json!({"Status": "Started"}));
+    OrthancCodeSuccess
+}
+
+// `GET /vara/transfers/jobs` lists the transfer jobs that are being followed
+// (see `orthanc::jobs`).
+extern "C" fn on_transfer_jobs_request(
+    output: *mut orthanc::plugin::OrthancPluginRestOutput,
+    _url: *const c_char,
+    request: *const orthanc::plugin::OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let method = unsafe { (*request).method };
+    if method != orthanc::plugin::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get {
+        orthanc::plugin::send_method_not_allowed(output, "GET");
+        return OrthancCodeSuccess;
+    }
+
+    let jobs = orthanc::jobs::get_transfer_jobs();
+    orthanc::plugin::answer_json(output, &serde_json::json!(jobs));
+    OrthancCodeSuccess
+}
+
+// `GET /vara/transfers/retries` lists the failed transfers waiting for their
+// next attempt (see `orthanc::retries`).
+extern "C" fn on_retries_request(
+    output: *mut orthanc::plugin::OrthancPluginRestOutput,
+    _url: *const c_char,
+    request: *const orthanc::plugin::OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let method = unsafe { (*request).method };
+    if method != orthanc::plugin::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get {
+        orthanc::plugin::send_method_not_allowed(output, "GET");
+        return OrthancCodeSuccess;
+    }
+
+    let pending = orthanc::retries::get_pending();
+    orthanc::plugin::answer_json(output, &serde_json::json!(pending));
+    OrthancCodeSuccess
+}
+
+// `GET /vara/transfers/dead-letters` lists the transfers that used up their
+// attempts, along with their last error.
+extern "C" fn on_dead_letters_request(
+    output: *mut orthanc::plugin::OrthancPluginRestOutput,
+    _url: *const c_char,
+    request: *const orthanc::plugin::OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let method = unsafe { (*request).method };
+    if method != orthanc::plugin::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get {
+        orthanc::plugin::send_method_not_allowed(output, "GET");
+        return OrthancCodeSuccess;
+    }
+
+    let dead_letters = orthanc::retries::get_dead_letters();
+    orthanc::plugin::answer_json(output, &serde_json::json!(dead_letters));
+    OrthancCodeSuccess
+}
+
+// `POST /vara/transfers/dead-letters/replay` moves every dead letter back to
+// the retry queue.
+extern "C" fn on_replay_dead_letters_request(
+    output: *mut orthanc::plugin::OrthancPluginRestOutput,
+    _url: *const c_char,
+    request: *const orthanc::plugin::OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let method = unsafe { (*request).method };
+    if method != orthanc::plugin::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Post {
+        orthanc::plugin::send_method_not_allowed(output, "POST");
+        return OrthancCodeSuccess;
+    }
+
+    let count = orthanc::retries::replay_dead_letters();
+    orthanc::plugin::info(&format!("Replaying {} dead-letter transfers", count));
+    orthanc::plugin::answer_json(output, &serde_json::json!({ "Replayed": count }));
+    OrthancCodeSuccess
+}
+
+// `GET /vara/transfers/verification-failures` lists the latest studies that
+// differed on a peer after their transfer.
+extern "C" fn on_verification_failures_request(
+    output: *mut orthanc::plugin::OrthancPluginRestOutput,
+    _url: *const c_char,
+    request: *const orthanc::plugin::OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let method = unsafe { (*request).method };
+    if method != orthanc::plugin::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get {
+        orthanc::plugin::send_method_not_allowed(output, "GET");
+        return OrthancCodeSuccess;
+    }
+
+    let failures = orthanc::verify::get_verification_failures();
+    orthanc::plugin::answer_json(output, &serde_json::json!(failures));
+    OrthancCodeSuccess
+}
+
+// `GET /vara/prefetch` lists the patients whose priors are (or were)
+// prefetched.
+extern "C" fn on_prefetch_request(
+    output: *mut orthanc::plugin::OrthancPluginRestOutput,
+    _url: *const c_char,
+    request: *const orthanc::plugin::OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let method = unsafe { (*request).method };
+    if method != orthanc::plugin::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get {
+        orthanc::plugin::send_method_not_allowed(output, "GET");
+        return OrthancCodeSuccess;
+    }
+
+    let prefetches = orthanc::prefetch::get_prefetches();
+    orthanc::plugin::answer_json(output, &serde_json::json!(prefetches));
+    OrthancCodeSuccess
+}
+
+// Returns the endpoints that can be queried for getting modality worklist
+// items, as configured in "VaraProxy" -> "Worklist" -> "Endpoints".
 //
-// TODO: Adjust everything to make use of Orthanc's configuration file.
-fn orthanc_modality_endpoints() -> Vec<String> {
+// If that option is missing, a single endpoint is built from the environment
+// variables `VARA_ORTHANC_MODALITY_ENDPOINT`, `VARA_ORTHANC_API_USER` and
+// `VARA_ORTHANC_API_PASSWORD`, which is how the plugin used to be configured.
+fn orthanc_modality_endpoints() -> Vec<WorklistEndpoint> {
+    if let Some(endpoints) = orthanc::plugin::get_worklist_endpoints() {
+        return endpoints;
+    }
+
     // By default, we send an API request to the same Orthanc instance that
-    // loads this plugin. Default endpoint
-    let default_value = vec![String::from(
-        "http://localhost:9042/modalities/orthanc/find-worklist",
-    )];
-    match env::var("VARA_ORTHANC_MODALITY_ENDPOINT") {
-        Ok(modality_endpoint) => {
-            vec![modality_endpoint.to_string()]
-        }
+    // loads this plugin.
+    let url = match env::var("VARA_ORTHANC_MODALITY_ENDPOINT") {
+        Ok(modality_endpoint) => modality_endpoint,
         error @ Err(_) => {
             orthanc::plugin::warning(&format!(
                 "VARA_ORTHANC_MODALITY_ENDPOINT not defined: {:?}",
                 error
             ));
-            default_value
+            String::from("http://localhost:9042/modalities/orthanc/find-worklist")
         }
-    }
+    };
+    vec![WorklistEndpoint {
+        name: url.clone(),
+        kind: WorklistEndpointType::Orthanc,
+        url,
+        path: None,
+        port: None,
+        username: Some(env::var("VARA_ORTHANC_API_USER").unwrap_or(String::from("admin"))),
+        password: Some(env::var("VARA_ORTHANC_API_PASSWORD").unwrap_or(String::from("password"))),
+        timeout_seconds: None,
+    }]
 }
 
 //
@@ -232,7 +558,13 @@ fn memory_buffer() -> OrthancPluginMemoryBuffer {
     buffer
 }
 
-fn create_dicom(dicom_json: String, target_buffer: *mut OrthancPluginMemoryBuffer) -> i32 {
+// `data_uris` tells whether string values starting with `data:` are binary
+// values to decode (see `dicom_json`), otherwise they are kept as they are.
+fn create_dicom(
+    dicom_json: String,
+    data_uris: bool,
+    target_buffer: *mut OrthancPluginMemoryBuffer,
+) -> i32 {
     #[repr(C)]
     struct CreateDicomParams {
         target: *mut OrthancPluginMemoryBuffer,
@@ -248,7 +580,11 @@ fn create_dicom(dicom_json: String, target_buffer: *mut OrthancPluginMemoryBuffe
         target: target_buffer,
         json: json_cstr.as_ptr(),
         pixel_data: std::ptr::null(),
-        flags: orthanc::plugin::OrthancPluginCreateDicomFlags_OrthancPluginCreateDicomFlags_None,
+        flags: if data_uris {
+            orthanc::plugin::OrthancPluginCreateDicomFlags_OrthancPluginCreateDicomFlags_DecodeDataUriScheme
+        } else {
+            orthanc::plugin::OrthancPluginCreateDicomFlags_OrthancPluginCreateDicomFlags_None
+        },
         private_creator: private_creator.as_ptr() as *const c_char,
     };
 
@@ -289,6 +625,71 @@ fn dicom_matches_query(
     unsafe { (*params.is_match) != 0 }
 }
 
+// Returns the C-FIND query received from the modality as a JSON object with
+// human-readable tag names (e.g. `{"PatientName": "DOE^*", ...}`), which is the
+// format expected in the `Query` field of Orthanc's `find-worklist` route.
+fn worklist_query_json(
+    query: *const OrthancPluginWorklistQuery,
+) -> Result<JsonValue, Box<dyn std::error::Error>> {
+    let mut buffer = memory_buffer();
+    let buffer_ptr = &mut buffer as *mut OrthancPluginMemoryBuffer;
+    let mut params = orthanc::plugin::_OrthancPluginWorklistQueryOperation {
+        query,
+        dicom: std::ptr::null(),
+        size: 0,
+        isMatch: std::ptr::null_mut(),
+        target: buffer_ptr,
+    };
+    let error_code = orthanc::plugin::invoke_orthanc_service(
+        orthanc::plugin::_OrthancPluginService__OrthancPluginService_WorklistGetDicomQuery,
+        &mut params as *mut orthanc::plugin::_OrthancPluginWorklistQueryOperation as *mut c_void,
+    );
+    if error_code != OrthancCodeSuccess {
+        return Err(format!(
+            "WorklistGetDicomQuery failed with error code {}",
+            error_code
+        )
+        .into());
+    }
+
+    let query_json = dicom_buffer_to_json(
+        buffer_ptr,
+        orthanc::plugin::OrthancPluginDicomToJsonFormat_OrthancPluginDicomToJsonFormat_Human,
+    );
+    orthanc::plugin::free_buffer(buffer_ptr);
+    query_json
+}
+
+fn dicom_buffer_to_json(
+    dicom: *const OrthancPluginMemoryBuffer,
+    format: orthanc::plugin::OrthancPluginDicomToJsonFormat,
+) -> Result<JsonValue, Box<dyn std::error::Error>> {
+    let dicom_buffer = unsafe { &(*dicom) };
+    let mut result: *mut c_char = std::ptr::null_mut();
+    let mut params = orthanc::plugin::_OrthancPluginDicomToJson {
+        result: &mut result as *mut *mut c_char,
+        instanceId: std::ptr::null(),
+        buffer: dicom_buffer.data,
+        size: dicom_buffer.size,
+        format,
+        flags: orthanc::plugin::OrthancPluginDicomToJsonFlags_OrthancPluginDicomToJsonFlags_SkipGroupLengths,
+        maxStringLength: 0,
+    };
+    let error_code = orthanc::plugin::invoke_orthanc_service(
+        orthanc::plugin::_OrthancPluginService__OrthancPluginService_DicomBufferToJson,
+        &mut params as *mut orthanc::plugin::_OrthancPluginDicomToJson as *mut c_void,
+    );
+    if error_code != OrthancCodeSuccess || result.is_null() {
+        return Err(format!("DicomBufferToJson failed with error code {}", error_code).into());
+    }
+
+    let json_str = unsafe { std::ffi::CStr::from_ptr(result) }
+        .to_string_lossy()
+        .to_string();
+    orthanc::plugin::free_string(result);
+    Ok(serde_json::from_str(&json_str)?)
+}
+
 fn add_worklist_query_answer(
     answers: *mut OrthancPluginWorklistAnswers,
     query: *const OrthancPluginWorklistQuery,
@@ -308,6 +709,19 @@ fn add_worklist_query_answer(
     );
 }
 
+fn mark_worklist_answers_incomplete(answers: *mut OrthancPluginWorklistAnswers) {
+    let mut params = orthanc::plugin::_OrthancPluginWorklistAnswersOperation {
+        answers,
+        query: std::ptr::null(),
+        dicom: std::ptr::null(),
+        size: 0,
+    };
+    orthanc::plugin::invoke_orthanc_service(
+        orthanc::plugin::_OrthancPluginService__OrthancPluginService_WorklistMarkIncomplete,
+        &mut params as *mut orthanc::plugin::_OrthancPluginWorklistAnswersOperation as *mut c_void,
+    );
+}
+
 //
 
 extern "C" fn on_change(
@@ -335,36 +749,38 @@ extern "C" fn on_change(
         change_type, resource_type, resource_id
     ));
 
-    // Transfer in a separate thread. (Note the warning here:
+    let is_stable_study = change_type
+        == orthanc::plugin::OrthancPluginChangeType_OrthancPluginChangeType_StableStudy
+        && resource_type
+            == orthanc::plugin::OrthancPluginResourceType_OrthancPluginResourceType_Study;
+    let study_id = match resource_id {
+        Some(study_id) if is_stable_study => study_id,
+        _ => return orthanc::plugin::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+    };
+
+    // The study is written to the on-disk queue before returning, so that it
+    // is routed even if routing fails for a while or Orthanc stops first.
+    let routing = match orthanc::queue::enqueue_routing(&study_id) {
+        Ok(routing) => routing,
+        Err(error) => {
+            orthanc::plugin::error(&format!(
+                "Failed to queue the routing of study {}: {:?}",
+                study_id, error
+            ));
+            let mut routing = orthanc::queue::new_transfer("", "studies", vec![study_id]);
+            routing.unrouted = true;
+            routing
+        }
+    };
+
+    // Routing and transfers happen in a separate thread. (Note the warning
+    // here:
     // https://sdk.orthanc-server.com/group__Callbacks.html#ga78140887a94f1afb067a15db5ee4099c
-    // ). This needs to happen in a separate thread.
+    // ).
     orthanc::plugin::get_threadpool().execute(move || {
-        if change_type
-            == orthanc::plugin::OrthancPluginChangeType_OrthancPluginChangeType_StableStudy
-            && resource_id.is_some()
-            && resource_type
-            == orthanc::plugin::OrthancPluginResourceType_OrthancPluginResourceType_Study
-        {
-            // Let's assume that we can handle one endpoint for the time being.
-            let endpoint = orthanc::plugin::get_local_endpoint();
-            let orthanc_client =
-                orthanc::OrthancClient::new(&endpoint.url, &endpoint.username, &endpoint.password);
-
-            match orthanc_client.transfer_studies(
-                &orthanc::plugin::get_peer_identifier(),
-                vec![resource_id.clone().unwrap().clone()],
-            ) {
-                Ok(response) => orthanc::plugin::info(&format!(
-                    "Successfully transferred study: {} {:?}",
-                    resource_id.unwrap(),
-                    response
-                )),
-                error => orthanc::plugin::info(&format!(
-                    "Encountered error while transferring a study: {:?}",
-                    error
-                )),
-            }
-        }
+        let orthanc_client = orthanc::get_local_client();
+        // Failed routings and transfers are retried, see `orthanc::retries`.
+        orthanc::retries::attempt(&orthanc_client, routing);
     });
 
     orthanc::plugin::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
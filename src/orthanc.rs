@@ -1,11 +1,28 @@
-use reqwest::Result;
+use serde_json as json;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::Mutex;
 pub mod http;
+pub mod jobs;
 pub mod plugin;
+pub mod prefetch;
+pub mod pull;
+pub mod queue;
+pub mod retention;
+pub mod retries;
+pub mod routing;
+pub mod schedule;
+pub mod stow;
+pub mod verify;
 
+use crate::dates;
+use http::ModalityStoreOptions;
 pub use http::OrthancClient;
+use http::Result;
+use http::StoreTarget;
+use routing::Router;
 
 #[derive(Debug)]
 pub struct Endpoint {
@@ -39,50 +56,654 @@ impl OrthancClient {
     }
 }
 
-pub fn sync_instances() -> Result<()> {
-    let local_endpoint = plugin::get_local_endpoint();
-    let peer_endpoint = plugin::get_peer_endpoint().unwrap();
-    plugin::info(&format!(
-        "Synchronizing studies between: {} -> {}",
-        local_endpoint, peer_endpoint
-    ));
+// How the local Orthanc sends resources to the destination named
+// `peer_identifier` (see `plugin::Destination`), or `None` for STOW-RS
+// destinations, which are not reached through Orthanc (see `stow`).
+pub fn get_store_target(peer_identifier: &str) -> Option<StoreTarget> {
+    let destination = plugin::get_destination(peer_identifier);
+    match destination.kind {
+        plugin::DestinationType::Peer => Some(StoreTarget::Peer(destination.name)),
+        plugin::DestinationType::Modality => Some(StoreTarget::Modality(
+            destination.name,
+            ModalityStoreOptions {
+                move_originator_aet: destination.move_originator_aet,
+                move_originator_id: destination.move_originator_id,
+                timeout: destination.timeout_seconds,
+                storage_commitment: destination.storage_commitment,
+            },
+        )),
+        plugin::DestinationType::StowRs => None,
+    }
+}
+
+// Transfers resources to a peer, either by waiting for the upload to
+// complete or by submitting an Orthanc job followed by `jobs` (see
+// "VaraProxy" -> "Transfers" -> "Asynchronous"), in which case the ID of the
+// job is returned (the job is verified once it succeeded, see `jobs`).
+// `queue_id` is the ID of the transfer in the on-disk `queue`, if any.
+//
+// Transfers are held to the upload cap, see `schedule`.
+// Completed transfers are recorded as deliveries, see `retention`.
+pub fn transfer_to_peer(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    resource_type: &str,
+    resource_ids: Vec<String>,
+    queue_id: Option<&str>,
+) -> Result<Option<String>> {
+    let destination = plugin::get_destination(peer_identifier);
+    // STOW-RS transfers are always synchronous, as Orthanc has no job to
+    // follow.
+    let is_stow = destination.kind == plugin::DestinationType::StowRs;
+    if plugin::get_asynchronous_transfers() && !is_stow {
+        if queue_id.is_some() {
+            return jobs::submit(
+                local_orthanc,
+                peer_identifier,
+                resource_type,
+                resource_ids,
+                queue_id,
+            )
+            .map(Some);
+        }
+        // The sync cursor moves past the resources once the job is
+        // submitted, but jobs are only followed in memory: the transfer is
+        // written to the on-disk queue, so that it is attempted again if
+        // Orthanc restarts before the job succeeded, and dead-lettered (see
+        // `POST /vara/transfers/dead-letters/replay`) if the job is
+        // abandoned.
+        let mut transfer =
+            queue::new_transfer(peer_identifier, resource_type, resource_ids.clone());
+        transfer.backlog = true;
+        let queue_id = match queue::save(&transfer) {
+            Ok(()) => Some(transfer.id.as_str()),
+            Err(error) => {
+                plugin::error(&format!(
+                    "Failed to queue the transfer of {:?} to {}: {:?}",
+                    resource_ids, peer_identifier, error
+                ));
+                None
+            }
+        };
+        let submitted = jobs::submit(
+            local_orthanc,
+            peer_identifier,
+            resource_type,
+            resource_ids,
+            queue_id,
+        );
+        if submitted.is_err() && queue_id.is_some() {
+            // Not moving the cursor, the next sync submits it again.
+            retries::remove_from_queue(&transfer.id);
+        }
+        return submitted.map(Some);
+    }
+
+    let study_versions = retention::get_study_versions(
+        local_orthanc,
+        peer_identifier,
+        resource_type,
+        &resource_ids,
+    )?;
+    if let Some(store_target) = get_store_target(peer_identifier) {
+        for batch in schedule::get_batches(local_orthanc, resource_type, resource_ids.clone())? {
+            schedule::wait_for_upload(peer_identifier, batch.size);
+            local_orthanc.transfer_entities(&store_target, batch.ids)?;
+        }
+        if let Err(error) =
+            verify::verify_transfer(local_orthanc, peer_identifier, resource_type, &resource_ids)
+        {
+            // The periodic sync does not send again studies that are on the
+            // peer, so they are queued to be sent again.
+            if queue_id.is_none() {
+                retries::retry_later(
+                    peer_identifier,
+                    resource_type,
+                    resource_ids,
+                    &error.to_string(),
+                );
+            }
+            return Err(error);
+        }
+    } else {
+        stow::store(local_orthanc, &destination, resource_type, resource_ids)?;
+    }
+    retention::record_delivery(peer_identifier, &study_versions);
+    Ok(None)
+}
+
+// Global property holding, for every peer, the sequence number of the last
+// change of the local `/changes` feed handled by `sync_instances`, as a JSON
+// object (`{"archive": 1234, "inference": 1200}`). Arbitrarily chosen,
+// properties below 1024 are reserved by Orthanc.
+const SYNC_CURSOR_PROPERTY: i32 = 8420;
 
-    let local_orthanc = OrthancClient::new(
+// Global property holding, for every peer, when it was last fully reconciled
+// (see `reconcile_instances`), in seconds since the UNIX epoch, as a JSON
+// object.
+const FULL_RECONCILE_PROPERTY: i32 = 8421;
+
+// Number of changes requested per page of the `/changes` feed.
+const CHANGES_PAGE_SIZE: u64 = 100;
+
+// Periodic syncs and on-demand reconciles must not run concurrently, as both
+// move the sync cursors.
+static SYNC_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn get_local_client() -> OrthancClient {
+    let local_endpoint = plugin::get_local_endpoint();
+    OrthancClient::new(
         &local_endpoint.url,
         &local_endpoint.username,
         &local_endpoint.password,
-    );
+    )
+}
 
-    let peer_orthanc = OrthancClient::new(
+// Returns `None` if the peer is not configured in "OrthancPeers".
+pub fn get_peer_client(peer_identifier: &str) -> Option<OrthancClient> {
+    let peer_endpoint = plugin::get_peer_endpoint(peer_identifier)?;
+    Some(OrthancClient::new(
         &peer_endpoint.url,
         &peer_endpoint.username,
         &peer_endpoint.password,
-    );
-
-    let local_instances: HashSet<String> = local_orthanc.get_instance_ids()?.into_iter().collect();
-    let peer_instances: HashSet<String> = peer_orthanc.get_instance_ids()?.into_iter().collect();
-
-    // plugin::info(&format!(
-    //     "Local: {:?}, Remote: {:?}",
-    //     &local_studies, &peer_studies
-    // ));
-
-    let missing_instances: Vec<String> = local_instances
-        .into_iter()
-        .filter(|local_study_id| !peer_instances.contains(local_study_id))
-        .collect();
-    if !missing_instances.is_empty() {
-        plugin::info(&format!("Transferring instances: {:?}", &missing_instances));
-        match local_orthanc.transfer_instances(&plugin::get_peer_identifier(), missing_instances) {
-            Ok(_response) => plugin::info(&format!("Successfully transferred instances")),
-            Err(error) => {
-                plugin::info(&format!("Failed to transfer studies: {:?}", error));
-                return Err(error);
+    ))
+}
+
+// Runs `sync` for every destination. A failing destination does not prevent
+// the others from being synchronized, the first error is returned once all
+// destinations were handled.
+//
+// Only Orthanc peers can be listed, `sync` gets their client. DICOM
+// modalities and STOW-RS services are known to have the studies delivered
+// to them (see `retention::is_delivered`): they are synchronized by study,
+// from the stable studies of the `/changes` feed. Destinations outside of
+// their transfer window are skipped, see `schedule`.
+fn for_each_peer<F>(mut sync: F) -> Result<()>
+where
+    F: FnMut(&OrthancClient, &str, Option<&OrthancClient>) -> Result<()>,
+{
+    let local_orthanc = get_local_client();
+    let mut result = Ok(());
+    for destination in plugin::get_destinations() {
+        let peer_identifier = destination.name;
+        if let Some(window_opening) = schedule::get_window_opening(&peer_identifier, true) {
+            plugin::info(&format!(
+                "Not synchronizing peer {} outside of its transfer window, which opens in {} minutes.",
+                peer_identifier,
+                window_opening.saturating_sub(dates::now()) / 60
+            ));
+            continue;
+        }
+        let peer_orthanc = match destination.kind {
+            plugin::DestinationType::Peer => {
+                let peer_endpoint = match plugin::get_peer_endpoint(&peer_identifier) {
+                    Some(peer_endpoint) => peer_endpoint,
+                    None => continue,
+                };
+                plugin::info(&format!(
+                    "Synchronizing studies between: {} -> {}",
+                    local_orthanc.url, peer_endpoint
+                ));
+                Some(OrthancClient::new(
+                    &peer_endpoint.url,
+                    &peer_endpoint.username,
+                    &peer_endpoint.password,
+                ))
+            }
+            plugin::DestinationType::Modality | plugin::DestinationType::StowRs => {
+                plugin::info(&format!(
+                    "Synchronizing studies between: {} -> {}",
+                    local_orthanc.url, peer_identifier
+                ));
+                None
+            }
+        };
+
+        if let Err(error) = sync(&local_orthanc, &peer_identifier, peer_orthanc.as_ref()) {
+            plugin::error(&format!(
+                "Failed to synchronize peer {}: {:?}",
+                peer_identifier, error
+            ));
+            if result.is_ok() {
+                result = Err(error);
             }
         }
-    } else {
-        plugin::info("No new studies to sync.");
+    }
+    result
+}
+
+// Transfers the studies and instances received since the previous sync to
+// every peer, by following the `/changes` feed of the local Orthanc from the
+// cursor of the peer (see `SYNC_CURSOR_PROPERTY`). Only "StableStudy" and
+// "NewInstance" changes are looked at. With asynchronous transfers, the
+// cursor moves once the jobs are submitted, their transfers are kept in the
+// on-disk `queue` until the jobs succeed (see `transfer_to_peer`).
+//
+// With "VaraProxy" -> "Sync" -> "LookbackDays", the recent studies are also
+// compared with the peer, see `reconcile_recent_studies`, and peers without a
+// cursor (i.e. on their first sync) start from the latest change: older
+// studies only reach them through "FullReconcileIntervalHours". Otherwise,
+// peers without a cursor are reconciled instead, see `reconcile_instances`.
+// Only the resources routed to a peer (see `routing`)
+// are transferred to it.
+//
+// Destinations that cannot be listed (see `for_each_peer`) only get the
+// stable studies they were not delivered, new instances reach them with
+// their study once it is stable again.
+pub fn sync_instances() -> Result<()> {
+    let _guard = SYNC_LOCK.lock().unwrap();
+    let mut router = Router::new(get_local_client());
+    let lookback_days = plugin::get_sync_lookback_days();
+    for_each_peer(|local_orthanc, peer_identifier, peer_orthanc| {
+        let mut since = match (load_sync_cursors().get(peer_identifier), lookback_days) {
+            (Some(cursor), _) => *cursor,
+            // Older studies are left to the full reconcile.
+            (None, Some(lookback_days)) => {
+                plugin::info(&format!(
+                    "No sync cursor found for peer {}, reconciling the studies of the last {} days.",
+                    peer_identifier, lookback_days
+                ));
+                local_orthanc.get_last_change()?
+            }
+            (None, None) => {
+                plugin::info(&format!(
+                    "No sync cursor found for peer {}, reconciling all instances.",
+                    peer_identifier
+                ));
+                return reconcile(local_orthanc, peer_identifier, peer_orthanc, &mut router);
+            }
+        };
+
+        loop {
+            let changes = local_orthanc.get_changes(since, CHANGES_PAGE_SIZE)?;
+            let mut study_ids = vec![];
+            let mut instance_ids = vec![];
+            for change in changes.changes {
+                match change.change_type.as_str() {
+                    "StableStudy" => study_ids.push(change.id),
+                    "NewInstance" if peer_orthanc.is_some() => instance_ids.push(change.id),
+                    _ => (),
+                }
+            }
+            // Studies first, so that (unless transfers are asynchronous) the
+            // instances they contain are already on the peer when instances
+            // are looked at.
+            for (resource_type, ids) in [("studies", study_ids), ("instances", instance_ids)] {
+                transfer_missing(
+                    local_orthanc,
+                    peer_identifier,
+                    peer_orthanc,
+                    resource_type,
+                    ids,
+                    &mut router,
+                )?;
+            }
+
+            // The cursor only moves once the whole page has been handled, so
+            // that a failed sync is retried from the same page.
+            since = changes.last;
+            store_sync_cursor(peer_identifier, since);
+            if changes.done {
+                break;
+            }
+        }
+
+        match lookback_days {
+            Some(lookback_days) => reconcile_recent_studies(
+                local_orthanc,
+                peer_identifier,
+                peer_orthanc,
+                lookback_days,
+                &mut router,
+            ),
+            None => Ok(()),
+        }
+    })
+}
+
+// Transfers every local instance that is missing on a peer (and routed to
+// it), by comparing the full listings of both, and resets the sync cursor of
+// the peer to the latest change. Destinations that cannot be listed (see
+// `for_each_peer`) get every local study routed to them that they were not
+// delivered. Used on the first sync of a peer (without "LookbackDays"),
+// every "VaraProxy" -> "Sync" -> "FullReconcileIntervalHours" (see
+// `reconcile_if_due`) and on demand (`POST /vara/sync/reconcile`).
+pub fn reconcile_instances() -> Result<()> {
+    let _guard = SYNC_LOCK.lock().unwrap();
+    let mut router = Router::new(get_local_client());
+    for_each_peer(|local_orthanc, peer_identifier, peer_orthanc| {
+        reconcile(local_orthanc, peer_identifier, peer_orthanc, &mut router)
+    })
+}
+
+// Reconciles the peers last reconciled more than "FullReconcileIntervalHours"
+// ago. For peers without a recorded reconcile, the first one is only
+// scheduled, a full interval from now.
+pub fn reconcile_if_due() -> Result<()> {
+    let interval = match plugin::get_full_reconcile_interval() {
+        Some(interval) => interval,
+        None => return Ok(()),
+    };
+    let now = dates::now();
+    let reconcile_times = load_full_reconcile_times();
+    let mut due_peers = HashSet::new();
+    for destination in plugin::get_destinations() {
+        match reconcile_times.get(&destination.name) {
+            Some(time) if time + interval.as_secs() <= now => {
+                due_peers.insert(destination.name);
+            }
+            Some(_) => (),
+            None => store_full_reconcile_time(&destination.name, now),
+        }
+    }
+    if due_peers.is_empty() {
+        return Ok(());
+    }
+
+    let _guard = SYNC_LOCK.lock().unwrap();
+    let mut router = Router::new(get_local_client());
+    for_each_peer(|local_orthanc, peer_identifier, peer_orthanc| {
+        if !due_peers.contains(peer_identifier) {
+            return Ok(());
+        }
+        plugin::info(&format!(
+            "Full reconcile of peer {} due, comparing all instances.",
+            peer_identifier
+        ));
+        reconcile(local_orthanc, peer_identifier, peer_orthanc, &mut router)
+    })
+}
+
+// Transfers the local studies of the last `lookback_days` days (by
+// StudyDate) that are missing on a peer (and routed to it), as well as the
+// missing instances of those the peer has fewer instances of. Both sides are
+// queried with `/tools/find`, so that only the recent part of the archive is
+// compared. Destinations that cannot be listed get the recent studies they
+// were not delivered.
+fn reconcile_recent_studies(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    peer_orthanc: Option<&OrthancClient>,
+    lookback_days: u64,
+    router: &mut Router,
+) -> Result<()> {
+    let query = json::json!({
+        "StudyDate": format!("{}-", dates::date_days_ago(lookback_days)),
+    });
+    let peer_orthanc = match peer_orthanc {
+        Some(peer_orthanc) => peer_orthanc,
+        None => {
+            let study_ids = local_orthanc.find_studies(&query)?;
+            return transfer_missing(
+                local_orthanc,
+                peer_identifier,
+                None,
+                "studies",
+                study_ids,
+                router,
+            );
+        }
+    };
+    let peer_studies: HashSet<String> = peer_orthanc.find_studies(&query)?.into_iter().collect();
+    let mut missing_studies = vec![];
+    let mut missing_instances = vec![];
+    for study_id in local_orthanc.find_studies(&query)? {
+        if !peer_studies.contains(&study_id) {
+            missing_studies.push(study_id);
+            continue;
+        }
+        // E.g. instances added to the study after it was sent, or lost by a
+        // transfer.
+        if !router.routes_to("studies", &study_id, peer_identifier)?
+            || local_orthanc.get_study_instance_count(&study_id)?
+                == peer_orthanc.get_study_instance_count(&study_id)?
+        {
+            continue;
+        }
+        let peer_instances: HashSet<String> = peer_orthanc
+            .get_study_instance_ids(&study_id)?
+            .into_iter()
+            .collect();
+        missing_instances.extend(
+            local_orthanc
+                .get_study_instance_ids(&study_id)?
+                .into_iter()
+                .filter(|instance_id| !peer_instances.contains(instance_id)),
+        );
+    }
+    transfer_missing(
+        local_orthanc,
+        peer_identifier,
+        Some(peer_orthanc),
+        "studies",
+        missing_studies,
+        router,
+    )?;
+    transfer_missing(
+        local_orthanc,
+        peer_identifier,
+        Some(peer_orthanc),
+        "instances",
+        missing_instances,
+        router,
+    )
+}
+
+fn reconcile(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    peer_orthanc: Option<&OrthancClient>,
+    router: &mut Router,
+) -> Result<()> {
+    // Changes happening while the listings are compared are picked up by the
+    // next incremental sync.
+    let last_change = local_orthanc.get_last_change()?;
+    // Recorded first, so that a failing reconcile is not run again on every
+    // periodic sync.
+    store_full_reconcile_time(peer_identifier, dates::now());
+
+    let peer_orthanc = match peer_orthanc {
+        Some(peer_orthanc) => peer_orthanc,
+        // Destinations that cannot be listed get the local studies they were
+        // not delivered, one page at a time.
+        None => {
+            for page in local_orthanc.iter_study_ids() {
+                transfer_missing(
+                    local_orthanc,
+                    peer_identifier,
+                    None,
+                    "studies",
+                    page?,
+                    router,
+                )?;
+            }
+            store_sync_cursor(peer_identifier, last_change);
+            return Ok(());
+        }
+    };
+
+    // Only the peer listing is kept in memory, local instances are compared
+    // and transferred one page at a time.
+    let mut peer_instances = HashSet::new();
+    for page in peer_orthanc.iter_instance_ids() {
+        peer_instances.extend(page?);
     }
 
+    let mut transferred_count = 0;
+    for page in local_orthanc.iter_instance_ids() {
+        let mut missing_instances = vec![];
+        for local_instance_id in page? {
+            if !peer_instances.contains(&local_instance_id)
+                && router.routes_to("instances", &local_instance_id, peer_identifier)?
+            {
+                missing_instances.push(local_instance_id);
+            }
+        }
+        if missing_instances.is_empty() {
+            continue;
+        }
+        transferred_count += missing_instances.len();
+        transfer_from_sync(
+            local_orthanc,
+            peer_identifier,
+            "instances",
+            missing_instances,
+        )?;
+    }
+    if transferred_count == 0 {
+        plugin::info(&format!("No new studies to sync to {}.", peer_identifier));
+    }
+
+    store_sync_cursor(peer_identifier, last_change);
     Ok(())
 }
+
+// Transfers the resources among `ids` that still exist locally, are routed to
+// the peer and are missing on it. `resource_type` is the name of the route
+// (`studies` or `instances`). Without `peer_orthanc`, the destination cannot
+// be listed: only studies are looked at, and those not delivered to it (see
+// `retention::is_delivered`) are missing.
+fn transfer_missing(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    peer_orthanc: Option<&OrthancClient>,
+    resource_type: &str,
+    ids: Vec<String>,
+    router: &mut Router,
+) -> Result<()> {
+    // The same study becomes stable again whenever instances are added to it.
+    let mut seen_ids = HashSet::new();
+    let mut missing_ids = vec![];
+    for id in ids {
+        if !seen_ids.insert(id.clone()) {
+            continue;
+        }
+        // Resources deleted since the change was recorded are skipped.
+        if local_orthanc.has_resource(resource_type, &id)?
+            && router.routes_to(resource_type, &id, peer_identifier)?
+            && !is_on_destination(
+                local_orthanc,
+                peer_identifier,
+                peer_orthanc,
+                resource_type,
+                &id,
+            )?
+        {
+            missing_ids.push(id);
+        }
+    }
+    if missing_ids.is_empty() {
+        return Ok(());
+    }
+    transfer_from_sync(local_orthanc, peer_identifier, resource_type, missing_ids)
+}
+
+fn is_on_destination(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    peer_orthanc: Option<&OrthancClient>,
+    resource_type: &str,
+    id: &str,
+) -> Result<bool> {
+    match peer_orthanc {
+        Some(peer_orthanc) => peer_orthanc.has_resource(resource_type, id),
+        None => retention::is_delivered(local_orthanc, peer_identifier, id),
+    }
+}
+
+// Transfers resources found missing on the peer by a sync or a reconcile.
+// While the upload to the destination is reserved by earlier transfers (see
+// `schedule`), they are queued instead, so that the sync does not wait.
+fn transfer_from_sync(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    resource_type: &str,
+    ids: Vec<String>,
+) -> Result<()> {
+    if let Some(start_at) = schedule::get_pacing_start_time(peer_identifier) {
+        retries::postpone(peer_identifier, resource_type, ids, start_at);
+        return Ok(());
+    }
+
+    plugin::info(&format!(
+        "Transferring {} to {}: {:?}",
+        resource_type, peer_identifier, &ids
+    ));
+    let _attempt = retries::Attempt::start(&ids);
+    match transfer_to_peer(local_orthanc, peer_identifier, resource_type, ids, None) {
+        Ok(None) => {
+            plugin::info(&format!("Successfully transferred {}", resource_type));
+            Ok(())
+        }
+        Ok(Some(_job_id)) => Ok(()),
+        Err(error) => {
+            plugin::info(&format!(
+                "Failed to transfer {}: {:?}",
+                resource_type, error
+            ));
+            Err(error)
+        }
+    }
+}
+
+fn load_sync_cursors() -> HashMap<String, u64> {
+    let cursors = match plugin::get_global_property(SYNC_CURSOR_PROPERTY) {
+        Some(cursors) => cursors,
+        None => return HashMap::new(),
+    };
+    // Before studies could be forwarded to several peers, the property held
+    // the cursor of the single peer.
+    if let Ok(cursor) = cursors.parse::<u64>() {
+        return plugin::get_peer_identifiers()
+            .into_iter()
+            .map(|peer_identifier| (peer_identifier, cursor))
+            .collect();
+    }
+    json::from_str(&cursors).unwrap_or_else(|error| {
+        plugin::warning(&format!(
+            "Ignoring invalid sync cursors {}: {:?}",
+            cursors, error
+        ));
+        HashMap::new()
+    })
+}
+
+fn load_full_reconcile_times() -> HashMap<String, u64> {
+    let times = match plugin::get_global_property(FULL_RECONCILE_PROPERTY) {
+        Some(times) => times,
+        None => return HashMap::new(),
+    };
+    json::from_str(&times).unwrap_or_else(|error| {
+        plugin::warning(&format!(
+            "Ignoring invalid full reconcile times {}: {:?}",
+            times, error
+        ));
+        HashMap::new()
+    })
+}
+
+fn store_full_reconcile_time(peer_identifier: &str, time: u64) {
+    let mut times = load_full_reconcile_times();
+    times.insert(peer_identifier.to_string(), time);
+    let times = json::to_string(&times).unwrap();
+    let error_code = plugin::set_global_property(FULL_RECONCILE_PROPERTY, &times);
+    if error_code != plugin::OrthancPluginErrorCode_OrthancPluginErrorCode_Success {
+        plugin::error(&format!(
+            "Failed to store the full reconcile times {}: error code {}",
+            times, error_code
+        ));
+    }
+}
+
+fn store_sync_cursor(peer_identifier: &str, cursor: u64) {
+    let mut cursors = load_sync_cursors();
+    cursors.insert(peer_identifier.to_string(), cursor);
+    let cursors = json::to_string(&cursors).unwrap();
+    let error_code = plugin::set_global_property(SYNC_CURSOR_PROPERTY, &cursors);
+    if error_code != plugin::OrthancPluginErrorCode_OrthancPluginErrorCode_Success {
+        plugin::error(&format!(
+            "Failed to store the sync cursors {}: error code {}",
+            cursors, error_code
+        ));
+    }
+}
@@ -1,7 +1,68 @@
 use reqwest::blocking::Client;
-use reqwest::Result;
+use reqwest::StatusCode;
+use serde::Deserialize;
 use serde::Serialize;
 use serde_json as json;
+use std::collections::HashMap;
+use std::fmt;
+
+// Number of IDs requested per page when listing entities.
+const ENTITIES_PAGE_SIZE: u64 = 1000;
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    // The response was received but is not what Orthanc is expected to send.
+    UnexpectedResponse(String),
+    // The destination refused to store some instances, as described by the
+    // elements of this list.
+    StoreFailed(Vec<String>),
+    // The transferred resources differ on the destination, as described by
+    // the elements of this list (see `verify`).
+    VerificationFailed(Vec<String>),
+    // The destination cannot be reached this way, e.g. STOW-RS destinations
+    // through an Orthanc job.
+    Unsupported(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(error) => write!(f, "{}", error),
+            Error::UnexpectedResponse(message) => write!(f, "Unexpected response: {}", message),
+            Error::StoreFailed(failures) => write!(
+                f,
+                "Failed to store {} instances: {}",
+                failures.len(),
+                failures.join(", ")
+            ),
+            Error::VerificationFailed(mismatches) => {
+                write!(f, "Verification failed: {}", mismatches.join(", "))
+            }
+            Error::Unsupported(message) => write!(f, "Unsupported: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(error) => Some(error),
+            Error::UnexpectedResponse(_)
+            | Error::StoreFailed(_)
+            | Error::VerificationFailed(_)
+            | Error::Unsupported(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Http(error)
+    }
+}
 
 #[derive(Debug)]
 pub struct OrthancClient {
@@ -11,67 +72,480 @@ pub struct OrthancClient {
     pub http_client: Client,
 }
 
-fn get_entities(
-    http_client: &Client,
-    url: &str,
-    username: &str,
-    password: &str,
-) -> Result<Vec<String>> {
-    let response = http_client
-        .get(url)
-        .basic_auth(&username, Some(&password))
-        .send()?;
-
-    if  response.error_for_status_ref().is_err() {
-        Err(response.error_for_status().err().unwrap())
-    } else {
-        let response: json::Value = response.json()?;
-        let mut ids = vec![];
-        for id in response.as_array().unwrap() {
-            ids.push(id.as_str().unwrap().to_string());
+// A page of the `/changes` feed of Orthanc.
+#[derive(Deserialize, Debug)]
+pub struct Changes {
+    #[serde(rename = "Changes")]
+    pub changes: Vec<Change>,
+    #[serde(rename = "Done")]
+    pub done: bool,
+    // Sequence number of the last change of the page, to be passed as `since`
+    // for the next page.
+    #[serde(rename = "Last")]
+    pub last: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Change {
+    #[serde(rename = "ChangeType")]
+    pub change_type: String,
+    #[serde(rename = "ResourceType")]
+    pub resource_type: String,
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Seq")]
+    pub seq: u64,
+}
+
+// Where the local Orthanc sends resources to: an Orthanc peer
+// (`/peers/{id}/store`) or a DICOM modality, with C-STORE
+// (`/modalities/{id}/store`).
+#[derive(Debug, Clone)]
+pub enum StoreTarget {
+    Peer(String),
+    Modality(String, ModalityStoreOptions),
+}
+
+impl StoreTarget {
+    fn route(&self) -> String {
+        match self {
+            StoreTarget::Peer(peer_identifier) => format!("peers/{}/store", peer_identifier),
+            StoreTarget::Modality(modality, _) => format!("modalities/{}/store", modality),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ModalityStoreOptions {
+    #[serde(rename = "MoveOriginatorAet", skip_serializing_if = "Option::is_none")]
+    pub move_originator_aet: Option<String>,
+    #[serde(rename = "MoveOriginatorID", skip_serializing_if = "Option::is_none")]
+    pub move_originator_id: Option<u16>,
+    // In seconds.
+    #[serde(rename = "Timeout", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(rename = "StorageCommitment")]
+    pub storage_commitment: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct StoreRequest<'a> {
+    #[serde(rename = "Asynchronous")]
+    asynchronous: bool,
+    #[serde(rename = "Resources")]
+    resources: Vec<String>,
+    #[serde(flatten)]
+    modality_options: Option<&'a ModalityStoreOptions>,
+}
+
+impl<'a> StoreRequest<'a> {
+    fn new(target: &'a StoreTarget, resources: Vec<String>, asynchronous: bool) -> Self {
+        StoreRequest {
+            asynchronous,
+            resources,
+            modality_options: match target {
+                StoreTarget::Peer(_) => None,
+                StoreTarget::Modality(_, options) => Some(options),
+            },
+        }
+    }
+}
+
+// The status of an Orthanc job, as returned by `/jobs/{id}`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Job {
+    #[serde(rename = "ID")]
+    pub id: String,
+    // "Pending", "Running", "Success", "Failure", "Paused" or "Retry".
+    #[serde(rename = "State")]
+    pub state: String,
+    #[serde(rename = "Progress", default)]
+    pub progress: u32,
+    #[serde(rename = "ErrorDescription", default)]
+    pub error_description: String,
+}
+
+// A study, series or instance, as returned by e.g. `/studies/{id}` or
+// `/studies/{id}/series`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Resource {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(rename = "MainDicomTags", default)]
+    pub main_dicom_tags: HashMap<String, String>,
+    // Only set for studies.
+    #[serde(rename = "PatientMainDicomTags", default)]
+    pub patient_main_dicom_tags: HashMap<String, String>,
+    // Only set for series.
+    #[serde(rename = "Instances", default)]
+    pub instances: Vec<String>,
+    // When the resource last received an instance, e.g. "20231018T101500".
+    #[serde(rename = "LastUpdate", default)]
+    pub last_update: String,
+}
+
+// Iterates over the IDs of the entities listed by a route such as
+// `/instances`, one page (`?since=&limit=`) at a time. Iteration stops after
+// the first error.
+pub struct EntityIds<'a> {
+    client: &'a OrthancClient,
+    url: String,
+    since: u64,
+    done: bool,
+}
+
+impl Iterator for EntityIds<'_> {
+    type Item = Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = self
+            .client
+            .get_entities_page(&self.url, self.since, ENTITIES_PAGE_SIZE);
+        match &page {
+            Ok(ids) => {
+                self.since += ids.len() as u64;
+                self.done = (ids.len() as u64) < ENTITIES_PAGE_SIZE;
+                if ids.is_empty() {
+                    return None;
+                }
+            }
+            Err(_) => self.done = true,
         }
-        Ok(ids)
+        Some(page)
     }
 }
 
 impl OrthancClient {
-    pub fn get_study_ids(self: &Self) -> Result<Vec<String>> {
-        let url = format!("{}/studies", self.url);
-        get_entities(&self.http_client, &url, &self.username, &self.password)
+    fn get_entities_page(&self, url: &str, since: u64, limit: u64) -> Result<Vec<String>> {
+        let response: json::Value = self
+            .http_client
+            .get(url)
+            .query(&[("since", since), ("limit", limit)])
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        let ids = response.as_array().ok_or_else(|| {
+            Error::UnexpectedResponse(format!(
+                "Expected a JSON array from {}, got: {}",
+                url, response
+            ))
+        })?;
+        ids.iter()
+            .map(|id| {
+                id.as_str().map(String::from).ok_or_else(|| {
+                    Error::UnexpectedResponse(format!(
+                        "Expected a string ID from {}, got: {}",
+                        url, id
+                    ))
+                })
+            })
+            .collect()
     }
 
-    pub fn get_instance_ids(self: &Self) -> Result<Vec<String>> {
-        let url = format!("{}/instances", self.url);
-        get_entities(&self.http_client, &url, &self.username, &self.password)
+    // `resource_type` is the name of the route (`studies`, `instances`, ...).
+    pub fn iter_entity_ids(&self, resource_type: &str) -> EntityIds<'_> {
+        EntityIds {
+            client: self,
+            url: format!("{}/{}", self.url, resource_type),
+            since: 0,
+            done: false,
+        }
+    }
+
+    pub fn iter_study_ids(&self) -> EntityIds<'_> {
+        self.iter_entity_ids("studies")
+    }
+
+    pub fn iter_instance_ids(&self) -> EntityIds<'_> {
+        self.iter_entity_ids("instances")
+    }
+
+    pub fn get_study_ids(&self) -> Result<Vec<String>> {
+        let pages: Vec<Vec<String>> = self.iter_study_ids().collect::<Result<_>>()?;
+        Ok(pages.concat())
+    }
+
+    pub fn get_instance_ids(&self) -> Result<Vec<String>> {
+        let pages: Vec<Vec<String>> = self.iter_instance_ids().collect::<Result<_>>()?;
+        Ok(pages.concat())
+    }
+
+    pub fn get_changes(&self, since: u64, limit: u64) -> Result<Changes> {
+        Ok(self
+            .http_client
+            .get(format!("{}/changes", self.url))
+            .query(&[("since", since), ("limit", limit)])
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    // Returns the sequence number of the most recent change (0 if there is
+    // none).
+    pub fn get_last_change(&self) -> Result<u64> {
+        let changes: Changes = self
+            .http_client
+            .get(format!("{}/changes?last", self.url))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(changes.last)
+    }
+
+    // `resource_type` is the name of the route (`studies`, `instances`, ...).
+    pub fn has_resource(&self, resource_type: &str, id: &str) -> Result<bool> {
+        let response = self
+            .http_client
+            .get(format!("{}/{}/{}", self.url, resource_type, id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    pub fn get_study(&self, study_id: &str) -> Result<Resource> {
+        Ok(self
+            .http_client
+            .get(format!("{}/studies/{}", self.url, study_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    // Size of the attachments of the study, in bytes.
+    pub fn get_study_disk_size(&self, study_id: &str) -> Result<u64> {
+        self.get_study_size_statistic(study_id, "DiskSize")
+    }
+
+    pub fn get_study_instance_count(&self, study_id: &str) -> Result<u64> {
+        let statistics = self.get_study_statistics(study_id)?;
+        statistics["CountInstances"]
+            .as_u64()
+            .ok_or_else(|| Error::UnexpectedResponse(statistics.to_string()))
+    }
+
+    fn get_study_size_statistic(&self, study_id: &str, name: &str) -> Result<u64> {
+        let statistics = self.get_study_statistics(study_id)?;
+        // Orthanc sends sizes as strings.
+        statistics[name]
+            .as_str()
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| Error::UnexpectedResponse(statistics.to_string()))
+    }
+
+    fn get_study_statistics(&self, study_id: &str) -> Result<json::Value> {
+        Ok(self
+            .http_client
+            .get(format!("{}/studies/{}/statistics", self.url, study_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    pub fn get_study_series(&self, study_id: &str) -> Result<Vec<Resource>> {
+        Ok(self
+            .http_client
+            .get(format!("{}/studies/{}/series", self.url, study_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    pub fn get_study_instance_ids(&self, study_id: &str) -> Result<Vec<String>> {
+        let instances: Vec<Resource> = self
+            .http_client
+            .get(format!("{}/studies/{}/instances", self.url, study_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(instances.into_iter().map(|instance| instance.id).collect())
+    }
+
+    // Returns `None` if Orthanc does not store the MD5 of attachments (see
+    // "StoreMD5ForAttachments").
+    pub fn get_instance_md5(&self, instance_id: &str) -> Result<Option<String>> {
+        let response = self
+            .http_client
+            .get(format!(
+                "{}/instances/{}/attachments/dicom/md5",
+                self.url, instance_id
+            ))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(
+            response.error_for_status()?.text()?.trim().to_string(),
+        ))
+    }
+
+    // Size of the DICOM file of the instance, in bytes.
+    pub fn get_instance_file_size(&self, instance_id: &str) -> Result<u64> {
+        let instance: json::Value = self
+            .http_client
+            .get(format!("{}/instances/{}", self.url, instance_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        instance["FileSize"]
+            .as_u64()
+            .ok_or_else(|| Error::UnexpectedResponse(instance.to_string()))
+    }
+
+    // Returns the DICOM file of the instance.
+    pub fn get_instance_file(&self, instance_id: &str) -> Result<Vec<u8>> {
+        Ok(self
+            .http_client
+            .get(format!("{}/instances/{}/file", self.url, instance_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .bytes()?
+            .to_vec())
+    }
+
+    // Returns the IDs of the studies matching `query` (DICOM tags and their
+    // value, e.g. `{"StudyDate": "20231018-"}`), see `/tools/find`.
+    pub fn find_studies(&self, query: &json::Value) -> Result<Vec<String>> {
+        Ok(self
+            .http_client
+            .post(format!("{}/tools/find", self.url))
+            .basic_auth(&self.username, Some(&self.password))
+            .json(&json::json!({ "Level": "Study", "Query": query }))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    // Stores a DICOM file.
+    pub fn upload_instance(&self, file: Vec<u8>) -> Result<()> {
+        self.http_client
+            .post(format!("{}/instances", self.url))
+            .basic_auth(&self.username, Some(&self.password))
+            .body(file)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    // Returns the study the instance belongs to.
+    pub fn get_instance_study(&self, instance_id: &str) -> Result<Resource> {
+        Ok(self
+            .http_client
+            .get(format!("{}/instances/{}/study", self.url, instance_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    // Returns `None` if the instance has no such metadata, e.g. "RemoteAET"
+    // for instances that were not received over DICOM.
+    pub fn get_instance_metadata(&self, instance_id: &str, name: &str) -> Result<Option<String>> {
+        let response = self
+            .http_client
+            .get(format!(
+                "{}/instances/{}/metadata/{}",
+                self.url, instance_id, name
+            ))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.text()?))
+    }
+
+    // `resource_type` is the name of the route (`studies`, `instances`, ...).
+    pub fn delete_resource(&self, resource_type: &str, id: &str) -> Result<()> {
+        self.http_client
+            .delete(format!("{}/{}/{}", self.url, resource_type, id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    // Returns `None` if the job is unknown, e.g. because it was dropped from
+    // the history of Orthanc (see the "JobsHistorySize" option).
+    pub fn get_job(&self, job_id: &str) -> Result<Option<Job>> {
+        let response = self
+            .http_client
+            .get(format!("{}/jobs/{}", self.url, job_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json()?))
+    }
+
+    // Only jobs in the "Failure" state can be resubmitted.
+    pub fn resubmit_job(&self, job_id: &str) -> Result<()> {
+        self.http_client
+            .post(format!("{}/jobs/{}/resubmit", self.url, job_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .body("")
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    // Submits the transfer as an Orthanc job and returns the ID of the job.
+    pub fn submit_transfer_job(
+        &self,
+        target: &StoreTarget,
+        entity_ids: Vec<String>,
+    ) -> Result<String> {
+        #[derive(Deserialize, Debug)]
+        struct JobResponse {
+            #[serde(rename = "ID")]
+            id: String,
+        }
+
+        let response: JobResponse = self
+            .http_client
+            .post(format!("{}/{}", self.url, target.route()))
+            .basic_auth(&self.username, Some(&self.password))
+            .json(&StoreRequest::new(target, entity_ids, true))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(response.id)
     }
 
     pub fn transfer_entities(
         self: &Self,
-        peer_identifier: &str,
+        target: &StoreTarget,
         entity_ids: Vec<String>,
     ) -> Result<()> {
-        #[derive(Serialize, Debug)]
-        struct PeerStoreRequest {
-            #[serde(rename = "Asynchronous")]
-            asynchronous: bool,
-            #[serde(rename = "Resources")]
-            resources: Vec<String>,
-        }
-
-        let request = PeerStoreRequest {
-            asynchronous: false,
-            resources: entity_ids,
-        };
+        let request = StoreRequest::new(target, entity_ids, false);
 
         let request = self
             .http_client
-            .post(format!("{}/peers/{}/store", self.url, peer_identifier))
+            .post(format!("{}/{}", self.url, target.route()))
             .basic_auth(&self.username, Some(&self.password))
             .json(&request)
             .build()?;
         let response = self.http_client.execute(request)?;
         if response.error_for_status_ref().is_err() {
-            return Err(response.error_for_status().err().unwrap());
+            return Err(response.error_for_status().err().unwrap().into());
         } else {
             Ok(())
         }
@@ -82,7 +556,10 @@ impl OrthancClient {
         peer_identifier: &str,
         instance_ids: Vec<String>,
     ) -> Result<()> {
-        self.transfer_entities(peer_identifier, instance_ids)
+        self.transfer_entities(
+            &StoreTarget::Peer(peer_identifier.to_string()),
+            instance_ids,
+        )
     }
 
     pub fn transfer_studies(
@@ -90,6 +567,6 @@ impl OrthancClient {
         peer_identifier: &str,
         study_ids: Vec<String>,
     ) -> Result<()> {
-        self.transfer_entities(peer_identifier, study_ids)
+        self.transfer_entities(&StoreTarget::Peer(peer_identifier.to_string()), study_ids)
     }
 }
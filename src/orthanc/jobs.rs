@@ -0,0 +1,274 @@
+// Tracks the transfer jobs submitted to the local Orthanc when transfers are
+// asynchronous (see "VaraProxy" -> "Transfers" -> "Asynchronous"). A poller
+// thread follows every job through `/jobs/{id}` until it succeeds and its
+// transfer is verified (see `verify`), and resubmits failed jobs up to
+// "MaxJobSubmissions" times.
+//
+// Jobs that succeeded are forgotten (and removed from the on-disk `queue` if
+// they came from it), jobs that were abandoned are moved to the dead-letter
+// list of `retries`.
+
+use super::http::Error;
+use super::http::Result;
+use super::http::StoreTarget;
+use super::plugin;
+use super::queue;
+use super::retention;
+use super::retention::StudyVersion;
+use super::retries;
+use super::schedule;
+use super::verify;
+use super::OrthancClient;
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TransferJob {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Peer")]
+    pub peer: String,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<String>,
+    // "studies" or "instances".
+    #[serde(rename = "ResourceType")]
+    pub resource_type: String,
+    // Last known state of the Orthanc job.
+    #[serde(rename = "State")]
+    pub state: String,
+    #[serde(rename = "Progress")]
+    pub progress: u32,
+    #[serde(rename = "Submissions")]
+    pub submissions: u32,
+    // ID of the transfer in the on-disk queue, if any.
+    #[serde(rename = "QueueID", skip_serializing_if = "Option::is_none")]
+    pub queue_id: Option<String>,
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Recorded as delivered once the job succeeded, see `retention`.
+    #[serde(skip)]
+    pub study_versions: Vec<StudyVersion>,
+    // In bytes, reserved under the upload cap whenever the job is submitted
+    // (see `schedule`).
+    #[serde(skip)]
+    pub size: u64,
+}
+
+static TRANSFER_JOBS: Mutex<Vec<TransferJob>> = Mutex::new(vec![]);
+
+// Submits the transfer of `resources` to the peer (or modality) as an Orthanc
+// job, and returns the ID of the job. STOW-RS destinations have no job and
+// are refused.
+pub fn submit(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    resource_type: &str,
+    resources: Vec<String>,
+    queue_id: Option<&str>,
+) -> Result<String> {
+    let store_target = get_store_target(peer_identifier)?;
+    let study_versions =
+        retention::get_study_versions(local_orthanc, peer_identifier, resource_type, &resources)?;
+    // The job uploads as fast as it can, its size is reserved up front (see
+    // `schedule`).
+    let size = match plugin::get_max_upload_rate() {
+        Some(_) => schedule::get_instance_sizes(local_orthanc, resource_type, resources.clone())?
+            .into_iter()
+            .map(|(_, size)| size)
+            .sum(),
+        None => 0,
+    };
+    schedule::wait_for_upload(peer_identifier, size);
+    let id = local_orthanc.submit_transfer_job(&store_target, resources.clone())?;
+    plugin::info(&format!(
+        "Submitted transfer job {} to {}: {:?}",
+        id, peer_identifier, resources
+    ));
+    TRANSFER_JOBS.lock().unwrap().push(TransferJob {
+        id: id.clone(),
+        peer: peer_identifier.to_string(),
+        resources,
+        resource_type: resource_type.to_string(),
+        state: String::from("Pending"),
+        progress: 0,
+        submissions: 1,
+        queue_id: queue_id.map(String::from),
+        error: None,
+        study_versions,
+        size,
+    });
+    Ok(id)
+}
+
+pub fn get_transfer_jobs() -> Vec<TransferJob> {
+    TRANSFER_JOBS.lock().unwrap().clone()
+}
+
+// Starts the thread polling the tracked jobs, every
+// "VaraProxy" -> "Transfers" -> "JobPollIntervalSeconds".
+pub fn start_poller() {
+    // The poller runs for the whole lifetime of the plugin, it is not taken
+    // from the plugin's thread pool so as to not starve it.
+    thread::spawn(|| loop {
+        thread::sleep(plugin::get_job_poll_interval());
+        poll(&super::get_local_client());
+    });
+}
+
+fn poll(local_orthanc: &OrthancClient) {
+    let max_submissions = plugin::get_max_job_submissions();
+    let succeeded = update_jobs(local_orthanc, max_submissions);
+    if succeeded.is_empty() {
+        return;
+    }
+
+    // Verifying takes requests to the peers, which may be slow: the lock is
+    // not held meanwhile, so as to not delay submissions.
+    let verified: Vec<(String, Result<()>)> = succeeded
+        .into_iter()
+        .map(|job| {
+            let verified = verify::verify_transfer(
+                local_orthanc,
+                &job.peer,
+                &job.resource_type,
+                &job.resources,
+            );
+            (job.id, verified)
+        })
+        .collect();
+
+    let mut jobs = TRANSFER_JOBS.lock().unwrap();
+    for (id, verified) in verified {
+        let job = match jobs.iter_mut().find(|job| job.id == id) {
+            Some(job) => job,
+            None => continue,
+        };
+        if let Err(error) = verified {
+            job.state = String::from("VerificationFailed");
+            job.error = Some(error.to_string());
+            resubmit(local_orthanc, job, max_submissions);
+            continue;
+        }
+        retention::record_delivery(&job.peer, &job.study_versions);
+        if let Some(queue_id) = &job.queue_id {
+            retries::remove_from_queue(queue_id);
+        }
+    }
+    jobs.retain(|job| job.state != "Success" && job.state != "Abandoned");
+}
+
+// Updates the state of the tracked jobs and resubmits failed ones, then
+// returns (copies of) the jobs that succeeded, which are left to verify.
+// Requests only go to the local Orthanc, holding the lock does not delay
+// submissions for long.
+fn update_jobs(local_orthanc: &OrthancClient, max_submissions: u32) -> Vec<TransferJob> {
+    let mut jobs = TRANSFER_JOBS.lock().unwrap();
+    let mut succeeded = vec![];
+    for job in jobs.iter_mut() {
+        let status = match local_orthanc.get_job(&job.id) {
+            Ok(status) => status,
+            Err(error) => {
+                plugin::warning(&format!(
+                    "Failed to get the status of transfer job {}: {:?}",
+                    job.id, error
+                ));
+                continue;
+            }
+        };
+
+        match status {
+            Some(status) if status.state == "Failure" => {
+                job.state = status.state;
+                job.error = Some(status.error_description);
+                resubmit(local_orthanc, job, max_submissions);
+            }
+            Some(status) => {
+                if status.progress != job.progress || status.state != job.state {
+                    plugin::info(&format!(
+                        "Transfer job {}: {} ({}%)",
+                        job.id, status.state, status.progress
+                    ));
+                }
+                job.state = status.state;
+                job.progress = status.progress;
+                if job.state == "Success" {
+                    succeeded.push(job.clone());
+                }
+            }
+            // Jobs that are not finished are never dropped from the history
+            // of Orthanc, this one is likely lost to a restart of Orthanc.
+            None => {
+                job.state = String::from("Unknown");
+                job.error = Some(String::from("The job is unknown to Orthanc"));
+                resubmit(local_orthanc, job, max_submissions);
+            }
+        }
+    }
+    jobs.retain(|job| job.state != "Abandoned");
+    succeeded
+}
+
+// Failed jobs are resubmitted as they are, unknown jobs and jobs whose
+// transfer failed verification are replaced by a new job for the same
+// resources.
+fn resubmit(local_orthanc: &OrthancClient, job: &mut TransferJob, max_submissions: u32) {
+    if job.submissions >= max_submissions {
+        let error = format!(
+            "Transfer job {} failed after {} submissions: {}",
+            job.id,
+            job.submissions,
+            job.error.as_deref().unwrap_or_default()
+        );
+        let mut transfer =
+            queue::new_transfer(&job.peer, &job.resource_type, job.resources.clone());
+        if let Some(queue_id) = &job.queue_id {
+            transfer.id = queue_id.clone();
+        }
+        transfer.attempts = job.submissions;
+        retries::dead_letter(transfer, &error);
+        job.state = String::from("Abandoned");
+        return;
+    }
+
+    plugin::warning(&format!(
+        "Transfer job {} failed: {:?}. Resubmitting it.",
+        job.id, job.error
+    ));
+    let resubmitted = if job.state != "Failure" {
+        get_store_target(&job.peer)
+            .and_then(|store_target| {
+                local_orthanc.submit_transfer_job(&store_target, job.resources.clone())
+            })
+            .map(|id| job.id = id)
+    } else {
+        local_orthanc.resubmit_job(&job.id)
+    };
+    match resubmitted {
+        Ok(()) => {
+            // Without waiting, the lock is held: the next transfers to the
+            // destination wait instead.
+            if let Some(rate) = plugin::get_max_upload_rate() {
+                schedule::reserve(&job.peer, rate, job.size);
+            }
+            job.submissions += 1;
+            job.state = String::from("Pending");
+            job.progress = 0;
+        }
+        // Retried on the next poll.
+        Err(error) => plugin::warning(&format!(
+            "Failed to resubmit transfer job {}: {:?}",
+            job.id, error
+        )),
+    }
+}
+
+fn get_store_target(peer_identifier: &str) -> Result<StoreTarget> {
+    super::get_store_target(peer_identifier).ok_or_else(|| {
+        Error::Unsupported(format!(
+            "{} is a STOW-RS destination, it cannot be sent to by an Orthanc job",
+            peer_identifier
+        ))
+    })
+}
@@ -3,11 +3,15 @@ include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 use libc::c_char;
 use libc::c_void;
 use reqwest::blocking::Client as HttpClient;
+use serde::Deserialize;
 use serde_json as json;
+use std::collections::HashMap;
 use std::env;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::path::PathBuf;
 use std::sync::RwLock;
+use std::time::Duration;
 use threadpool::ThreadPool;
 
 #[derive(Debug)]
@@ -37,6 +41,12 @@ pub fn get_config() -> json::Value {
     PLUGIN_STATE.read().unwrap().config.clone().unwrap()
 }
 
+pub fn get_http_client() -> HttpClient {
+    // Cloning a `reqwest::blocking::Client` creates a new handle to same
+    // client.
+    PLUGIN_STATE.read().unwrap().http_client.clone().unwrap()
+}
+
 pub fn get_threadpool() -> ThreadPool {
     // Cloning a ThreadPool creates a new handle from the same threadpool:
     // https://docs.rs/threadpool/latest/src/threadpool/lib.rs.html#639-682
@@ -59,6 +69,82 @@ pub fn free_buffer(buffer: *mut OrthancPluginMemoryBuffer) {
     unsafe { (&*context).Free.unwrap()((*buffer).data as *mut c_void) };
 }
 
+pub fn free_string(string: *mut c_char) {
+    let context = get_context();
+    unsafe { (&*context).Free.unwrap()(string as *mut c_void) };
+}
+
+// Global properties are persisted in the Orthanc database, properties below
+// 1024 are reserved by Orthanc. Returns `None` if the property is not set.
+pub fn get_global_property(property: i32) -> Option<String> {
+    let mut result: *mut c_char = std::ptr::null_mut();
+    let default_value = CString::new("").unwrap();
+    let mut params = _OrthancPluginGlobalProperty {
+        result: &mut result as *mut *mut c_char,
+        property,
+        value: default_value.as_ptr(),
+    };
+    let error_code = invoke_orthanc_service(
+        _OrthancPluginService__OrthancPluginService_GetGlobalProperty,
+        &mut params as *mut _OrthancPluginGlobalProperty as *mut c_void,
+    );
+    if error_code != OrthancPluginErrorCode_OrthancPluginErrorCode_Success || result.is_null() {
+        return None;
+    }
+    let value = unsafe { CStr::from_ptr(result) }
+        .to_string_lossy()
+        .to_string();
+    free_string(result);
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+pub fn set_global_property(property: i32, value: &str) -> OrthancPluginErrorCode {
+    let value = CString::new(value).unwrap();
+    let mut params = _OrthancPluginGlobalProperty {
+        result: std::ptr::null_mut(),
+        property,
+        value: value.as_ptr(),
+    };
+    invoke_orthanc_service(
+        _OrthancPluginService__OrthancPluginService_SetGlobalProperty,
+        &mut params as *mut _OrthancPluginGlobalProperty as *mut c_void,
+    )
+}
+
+// REST
+// ----------------------------------------------------------------------------
+pub fn answer_json(output: *mut OrthancPluginRestOutput, value: &json::Value) {
+    let answer = value.to_string();
+    let mime_type = CString::new("application/json").unwrap();
+    let mut params = _OrthancPluginAnswerBuffer {
+        output,
+        answer: answer.as_ptr() as *const c_void,
+        answerSize: answer.len() as u32,
+        mimeType: mime_type.as_ptr(),
+    };
+    invoke_orthanc_service(
+        _OrthancPluginService__OrthancPluginService_AnswerBuffer,
+        &mut params as *mut _OrthancPluginAnswerBuffer as *mut c_void,
+    );
+}
+
+// `allowed_methods` is a comma-separated list, e.g. "GET,POST".
+pub fn send_method_not_allowed(output: *mut OrthancPluginRestOutput, allowed_methods: &str) {
+    let allowed_methods = CString::new(allowed_methods).unwrap();
+    let mut params = _OrthancPluginOutputPlusArgument {
+        output,
+        argument: allowed_methods.as_ptr(),
+    };
+    invoke_orthanc_service(
+        _OrthancPluginService__OrthancPluginService_SendMethodNotAllowed,
+        &mut params as *mut _OrthancPluginOutputPlusArgument as *mut c_void,
+    );
+}
+
 // Logging
 // ----------------------------------------------------------------------------
 enum LogLevel {
@@ -137,39 +223,403 @@ pub fn get_local_endpoint() -> super::Endpoint {
     }
 }
 
-pub fn get_peer_identifier() -> String {
-    let c = get_config();
-    c["VaraProxy"]["Peer"].as_str().unwrap().to_string()
+// A destination studies are forwarded to. Configured as an entry of
+// "VaraProxy" -> "Peers". A string is the identifier of an Orthanc peer in
+// "OrthancPeers", as is an object without "Type":
+//
+//   {"Name": "archive"}
+//
+// A destination of type "Modality" is the identifier of a DICOM modality in
+// "DicomModalities", studies are sent to it with C-STORE. "TimeoutSeconds"
+// applies to the DICOM association, "StorageCommitment" requests a storage
+// commitment once the instances are sent:
+//
+//   {"Name": "pacs", "Type": "Modality", "MoveOriginatorAet": "VARA",
+//    "MoveOriginatorID": 1, "TimeoutSeconds": 60, "StorageCommitment": true}
+//
+// A destination of type "STOW-RS" is the DICOMweb service whose base URL is
+// "Url", the instances are read from the local Orthanc and uploaded by the
+// plugin (see `orthanc::stow`). It authenticates with "Username" and
+// "Password", with "BearerToken", or with custom "Headers":
+//
+//   {"Name": "cloud", "Type": "STOW-RS", "Url": "https://archive/dicomweb",
+//    "BearerToken": "secret", "Headers": {"X-Tenant": "vara"},
+//    "TimeoutSeconds": 120}
+//
+// Any destination can have a "TransferWindow", see `TransferWindow`.
+//
+// The single "VaraProxy" -> "Peer" is still supported.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Destination {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Type", default)]
+    pub kind: DestinationType,
+    #[serde(rename = "MoveOriginatorAet")]
+    pub move_originator_aet: Option<String>,
+    #[serde(rename = "MoveOriginatorID")]
+    pub move_originator_id: Option<u16>,
+    #[serde(rename = "TimeoutSeconds")]
+    pub timeout_seconds: Option<u64>,
+    #[serde(rename = "StorageCommitment", default)]
+    pub storage_commitment: bool,
+    #[serde(rename = "Url", default)]
+    pub url: String,
+    #[serde(rename = "Username")]
+    pub username: Option<String>,
+    #[serde(rename = "Password")]
+    pub password: Option<String>,
+    #[serde(rename = "BearerToken")]
+    pub bearer_token: Option<String>,
+    #[serde(rename = "Headers", default)]
+    pub headers: HashMap<String, String>,
+    #[serde(rename = "TransferWindow")]
+    pub transfer_window: Option<TransferWindow>,
 }
 
-pub fn get_peer_endpoint() -> Option<super::Endpoint> {
-    let c = get_config();
-    if c["VaraProxy"] != json::json!(null) && c["VaraProxy"]["Peer"] != json::json!(null) {
-        let peer_identifier = c["VaraProxy"]["Peer"].as_str().unwrap();
+// The hours ("HH:MM", local time) between which transfers to a destination
+// may start, e.g. for the backlog to only use the link at night:
+//
+//   {"Start": "20:00", "End": "06:00", "StableStudies": false}
+//
+// The window holds back the transfers of the periodic sync and reconciles,
+// and those of "StableStudies" (forwarded as they arrive, see `on_change`)
+// only if set. Held back transfers wait in the queue until the window opens,
+// see `orthanc::schedule`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TransferWindow {
+    #[serde(rename = "Start")]
+    pub start: String,
+    #[serde(rename = "End")]
+    pub end: String,
+    #[serde(rename = "StableStudies", default)]
+    pub stable_studies: bool,
+}
 
-        if c["OrthancPeers"][peer_identifier] == json::json!(null) {
-            error(&format!(
-                "Please configure peer identifier: {}",
-                peer_identifier
-            ));
-            return None;
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DestinationType {
+    #[default]
+    Peer,
+    Modality,
+    #[serde(rename = "STOW-RS")]
+    StowRs,
+}
+
+impl Destination {
+    // An Orthanc peer, as configured by a plain string.
+    pub fn peer(name: &str) -> Self {
+        Destination {
+            name: name.to_string(),
+            ..Default::default()
         }
+    }
+}
 
-        let peer_coords: Vec<String> = c["OrthancPeers"][peer_identifier]
+pub fn get_destinations() -> Vec<Destination> {
+    let c = get_config();
+    let peers = &c["VaraProxy"]["Peers"];
+    if !peers.is_null() {
+        return peers
             .as_array()
-            .unwrap()
-            .into_iter()
-            .map(|v| v.as_str().unwrap().to_string())
+            .expect("Non-array provided for Peers option in VaraProxy plugin")
+            .iter()
+            .map(|peer| match peer.as_str() {
+                Some(peer_identifier) => Destination::peer(peer_identifier),
+                None => json::from_value(peer.clone())
+                    .expect("Invalid destination provided in Peers option in VaraProxy plugin"),
+            })
             .collect();
-        Some(super::Endpoint {
-            url: peer_coords[0].to_string(),
-            username: peer_coords[1].to_string(),
-            password: peer_coords[2].to_string(),
-        })
-    } else {
-        error("Please configure \"VaraProxy\" -> \"Peer\" in orthanc configuration.");
-        None
     }
+    match c["VaraProxy"]["Peer"].as_str() {
+        Some(peer_identifier) => vec![Destination::peer(peer_identifier)],
+        None => {
+            error("Please configure \"VaraProxy\" -> \"Peers\" in orthanc configuration.");
+            vec![]
+        }
+    }
+}
+
+// Destinations that are not configured in "VaraProxy" -> "Peers" (e.g. only
+// named by a routing rule) are Orthanc peers.
+pub fn get_destination(name: &str) -> Destination {
+    get_destinations()
+        .into_iter()
+        .find(|destination| destination.name == name)
+        .unwrap_or_else(|| Destination::peer(name))
+}
+
+// The names of the destinations studies are forwarded to, see `Destination`.
+pub fn get_peer_identifiers() -> Vec<String> {
+    get_destinations()
+        .into_iter()
+        .map(|destination| destination.name)
+        .collect()
+}
+
+pub fn get_peer_endpoint(peer_identifier: &str) -> Option<super::Endpoint> {
+    let c = get_config();
+    if c["OrthancPeers"][peer_identifier] == json::json!(null) {
+        error(&format!(
+            "Please configure peer identifier: {}",
+            peer_identifier
+        ));
+        return None;
+    }
+
+    let peer_coords: Vec<String> = c["OrthancPeers"][peer_identifier]
+        .as_array()
+        .unwrap()
+        .into_iter()
+        .map(|v| v.as_str().unwrap().to_string())
+        .collect();
+    Some(super::Endpoint {
+        url: peer_coords[0].to_string(),
+        username: peer_coords[1].to_string(),
+        password: peer_coords[2].to_string(),
+    })
+}
+
+// Chooses the peers a study is forwarded to from its DICOM tags. Configured
+// as an entry of "VaraProxy" -> "Routing" -> "Rules", the first matching rule
+// applies:
+//
+//   {"Match": {"Modality": "OT"}, "Destinations": []}
+//   {"Match": {"Modality": {"Regex": "^(CT|MR)$"}, "CallingAet": "CT*"},
+//    "Destinations": ["archive", "inference"]}
+//
+// A rule matches when every tag of "Match" matches, see `orthanc::routing`.
+// Studies matching no rule are forwarded to the default destinations.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TransferRoutingRule {
+    #[serde(rename = "Match", default)]
+    pub conditions: HashMap<String, TagPredicate>,
+    // Identifiers in "OrthancPeers", empty to keep the study local.
+    #[serde(rename = "Destinations")]
+    pub destinations: Vec<String>,
+}
+
+// A string compares for equality, unless it contains the "*" (any sequence of
+// characters) or "?" (any character) wildcards.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum TagPredicate {
+    Pattern(String),
+    Regex {
+        #[serde(rename = "Regex")]
+        regex: String,
+    },
+}
+
+pub fn get_transfer_routing_rules() -> Vec<TransferRoutingRule> {
+    let c = get_config();
+    let rules = &c["VaraProxy"]["Routing"]["Rules"];
+    if rules.is_null() {
+        return vec![];
+    }
+    json::from_value(rules.clone())
+        .expect("Invalid \"VaraProxy\" -> \"Routing\" -> \"Rules\" option in VaraProxy plugin")
+}
+
+// The peers studies matching no routing rule are forwarded to. Configured as
+// "VaraProxy" -> "Routing" -> "DefaultDestinations". Default: all the peers.
+pub fn get_default_destinations() -> Vec<String> {
+    let c = get_config();
+    let destinations = &c["VaraProxy"]["Routing"]["DefaultDestinations"];
+    if destinations.is_null() {
+        return get_peer_identifiers();
+    }
+    json::from_value(destinations.clone()).expect(
+        "Invalid \"VaraProxy\" -> \"Routing\" -> \"DefaultDestinations\" option in VaraProxy plugin",
+    )
+}
+
+// A source of modality worklist items. Configured as an entry of
+// "VaraProxy" -> "Worklist" -> "Endpoints". By default, an endpoint is an
+// upstream that answers Orthanc `find-worklist` queries:
+//
+//   {"Name": "ris-a", "Url": "http://ris-a:8042/modalities/ris/find-worklist",
+//    "Username": "admin", "Password": "password", "TimeoutSeconds": 10}
+//
+// An endpoint of type "Folder" reads worklist items from `.json` and `.wl`
+// files in a local directory:
+//
+//   {"Name": "scheduler", "Type": "Folder", "Path": "/var/lib/orthanc/worklists"}
+//
+// An endpoint of type "HL7" listens for HL7 v2 orders over MLLP on "Port" and
+// keeps the resulting worklist items in "Path":
+//
+//   {"Name": "ris-hl7", "Type": "HL7", "Port": 2575, "Path": "/var/lib/orthanc/hl7"}
+//
+// An endpoint of type "FHIR" fetches the active ServiceRequest resources (and
+// their Patient) of the FHIR R4 server whose base URL is "Url":
+//
+//   {"Name": "emr", "Type": "FHIR", "Url": "http://emr:8080/fhir",
+//    "Username": "orthanc", "Password": "secret"}
+//
+// An endpoint of type "UPS-RS" searches the scheduled workitems of the
+// DICOMweb service whose base URL is "Url":
+//
+//   {"Name": "scheduler", "Type": "UPS-RS", "Url": "http://pacs:8042/dicom-web"}
+#[derive(Deserialize, Debug, Clone)]
+pub struct WorklistEndpoint {
+    #[serde(rename = "Name", default)]
+    pub name: String,
+    #[serde(rename = "Type", default)]
+    pub kind: WorklistEndpointType,
+    #[serde(rename = "Url", default)]
+    pub url: String,
+    #[serde(rename = "Path")]
+    pub path: Option<PathBuf>,
+    #[serde(rename = "Port")]
+    pub port: Option<u16>,
+    #[serde(rename = "Username")]
+    pub username: Option<String>,
+    #[serde(rename = "Password")]
+    pub password: Option<String>,
+    #[serde(rename = "TimeoutSeconds")]
+    pub timeout_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WorklistEndpointType {
+    #[default]
+    Orthanc,
+    Folder,
+    #[serde(rename = "HL7")]
+    Hl7,
+    #[serde(rename = "FHIR")]
+    Fhir,
+    #[serde(rename = "UPS-RS")]
+    UpsRs,
+}
+
+// Returns `None` if "VaraProxy" -> "Worklist" -> "Endpoints" is not configured.
+pub fn get_worklist_endpoints() -> Option<Vec<WorklistEndpoint>> {
+    let c = get_config();
+    let endpoints = &c["VaraProxy"]["Worklist"]["Endpoints"];
+    if endpoints.is_null() {
+        return None;
+    }
+
+    let mut endpoints: Vec<WorklistEndpoint> = json::from_value(endpoints.clone()).expect(
+        "Invalid \"VaraProxy\" -> \"Worklist\" -> \"Endpoints\" option in VaraProxy plugin",
+    );
+    for endpoint in endpoints.iter_mut() {
+        match endpoint.kind {
+            WorklistEndpointType::Orthanc
+            | WorklistEndpointType::Fhir
+            | WorklistEndpointType::UpsRs
+                if endpoint.url.is_empty() =>
+            {
+                panic!("Missing \"Url\" for worklist endpoint in VaraProxy plugin")
+            }
+            WorklistEndpointType::Folder if endpoint.path.is_none() => {
+                panic!(
+                    "Missing \"Path\" for worklist endpoint of type \"Folder\" in VaraProxy plugin"
+                )
+            }
+            WorklistEndpointType::Hl7 if endpoint.path.is_none() => {
+                panic!("Missing \"Path\" for worklist endpoint of type \"HL7\" in VaraProxy plugin")
+            }
+            WorklistEndpointType::Hl7 if endpoint.port.is_none() => {
+                panic!("Missing \"Port\" for worklist endpoint of type \"HL7\" in VaraProxy plugin")
+            }
+            _ => (),
+        }
+        if endpoint.name.is_empty() {
+            endpoint.name = match &endpoint.path {
+                Some(path) if endpoint.url.is_empty() => path.display().to_string(),
+                _ => endpoint.url.clone(),
+            };
+        }
+    }
+    Some(endpoints)
+}
+
+// Restricts the worklist answered to the modalities whose calling AE title
+// (and, optionally, the AE title they called) match "CallingAet" (and
+// "CalledAet"). Configured as an entry of
+// "VaraProxy" -> "Worklist" -> "Routing" -> "Rules", the first matching rule
+// applies:
+//
+//   {"CallingAet": "CT1", "Endpoints": ["ris-a"],
+//    "ScheduledStationAETitles": ["CT1"], "Modalities": ["CT"]}
+//
+// Only the endpoints named in "Endpoints" are queried (all of them if not
+// set), and only the items with a scheduled procedure step for one of
+// "ScheduledStationAETitles" and one of "Modalities" are answered (no filter
+// if not set or empty).
+#[derive(Deserialize, Debug, Clone)]
+pub struct WorklistRoutingRule {
+    #[serde(rename = "CallingAet")]
+    pub calling_aet: String,
+    #[serde(rename = "CalledAet")]
+    pub called_aet: Option<String>,
+    #[serde(rename = "Endpoints")]
+    pub endpoints: Option<Vec<String>>,
+    #[serde(rename = "ScheduledStationAETitles", default)]
+    pub scheduled_station_ae_titles: Vec<String>,
+    #[serde(rename = "Modalities", default)]
+    pub modalities: Vec<String>,
+}
+
+// What happens to the queries of modalities matching no routing rule.
+// Configured as "VaraProxy" -> "Worklist" -> "Routing" -> "DefaultPolicy".
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WorklistRoutingPolicy {
+    // Query all endpoints, without filtering the answers.
+    #[default]
+    AllowAll,
+    // Answer with an empty worklist.
+    Deny,
+}
+
+pub fn get_worklist_routing_rules() -> Vec<WorklistRoutingRule> {
+    let c = get_config();
+    let rules = &c["VaraProxy"]["Worklist"]["Routing"]["Rules"];
+    if rules.is_null() {
+        return vec![];
+    }
+    json::from_value(rules.clone()).expect(
+        "Invalid \"VaraProxy\" -> \"Worklist\" -> \"Routing\" -> \"Rules\" option in VaraProxy plugin",
+    )
+}
+
+pub fn get_worklist_routing_policy() -> WorklistRoutingPolicy {
+    let c = get_config();
+    let policy = &c["VaraProxy"]["Worklist"]["Routing"]["DefaultPolicy"];
+    if policy.is_null() {
+        return WorklistRoutingPolicy::default();
+    }
+    json::from_value(policy.clone()).expect(
+        "Invalid \"VaraProxy\" -> \"Worklist\" -> \"Routing\" -> \"DefaultPolicy\" option in VaraProxy plugin",
+    )
+}
+
+// Directory holding the last response of every worklist endpoint. Defaults to
+// the working directory of the Orthanc process.
+pub fn get_worklist_cache_directory() -> PathBuf {
+    let c = get_config();
+    PathBuf::from(
+        c["VaraProxy"]["Worklist"]["CacheDirectory"]
+            .as_str()
+            .unwrap_or("."),
+    )
+}
+
+// Cached worklist responses older than this are not served. Unlimited if
+// "VaraProxy" -> "Worklist" -> "MaxStalenessSeconds" is not configured.
+pub fn get_worklist_max_staleness() -> Option<Duration> {
+    let c = get_config();
+    c["VaraProxy"]["Worklist"]["MaxStalenessSeconds"]
+        .as_u64()
+        .map(Duration::from_secs)
+}
+
+// The "StorageDirectory" of Orthanc, which defaults to "OrthancStorage".
+pub fn get_storage_directory() -> PathBuf {
+    let c = get_config();
+    PathBuf::from(c["StorageDirectory"].as_str().unwrap_or("OrthancStorage"))
 }
 
 pub fn get_sync_interval() -> u64 {
@@ -177,6 +627,268 @@ pub fn get_sync_interval() -> u64 {
     config["VaraProxy"]["PeriodicSyncIntervalSeconds"].as_u64().unwrap_or(600)
 }
 
+// The periodic sync also compares the studies of the last
+// "VaraProxy" -> "Sync" -> "LookbackDays" days (by StudyDate) with the peers.
+// No comparison if not set.
+pub fn get_sync_lookback_days() -> Option<u64> {
+    let config = get_config();
+    config["VaraProxy"]["Sync"]["LookbackDays"].as_u64()
+}
+
+// How often every instance is compared with the peers, see
+// `orthanc::reconcile_instances`. Configured as
+// "VaraProxy" -> "Sync" -> "FullReconcileIntervalHours". Never if not set.
+pub fn get_full_reconcile_interval() -> Option<Duration> {
+    let config = get_config();
+    config["VaraProxy"]["Sync"]["FullReconcileIntervalHours"]
+        .as_u64()
+        .map(|hours| Duration::from_secs(hours * 3600))
+}
+
+// Whether transfers to the peer are submitted as Orthanc jobs instead of
+// waiting for the upload to complete. Configured as
+// "VaraProxy" -> "Transfers" -> "Asynchronous". Default: false.
+pub fn get_asynchronous_transfers() -> bool {
+    let c = get_config();
+    match c["VaraProxy"]["Transfers"]["Asynchronous"] {
+        json::Value::Null => false,
+        json::Value::Bool(b) => b,
+        _ => {
+            panic!("Non-boolean provided for Transfers -> Asynchronous option in VaraProxy plugin")
+        }
+    }
+}
+
+// The average upload rate to every destination, in bytes per second, see
+// `orthanc::schedule`. Configured in KiB per second as
+// "VaraProxy" -> "Transfers" -> "MaxUploadKiBPerSecond". No cap if not set.
+pub fn get_max_upload_rate() -> Option<u64> {
+    let c = get_config();
+    c["VaraProxy"]["Transfers"]["MaxUploadKiBPerSecond"]
+        .as_u64()
+        .filter(|rate| *rate > 0)
+        .map(|rate| rate.saturating_mul(1024))
+}
+
+// Whether transfers of studies to Orthanc peers are verified once they are
+// done, see `orthanc::verify`. Configured as
+// "VaraProxy" -> "Transfers" -> "Verify". Default: true.
+pub fn get_transfer_verification() -> bool {
+    let c = get_config();
+    match c["VaraProxy"]["Transfers"]["Verify"] {
+        json::Value::Null => true,
+        json::Value::Bool(b) => b,
+        _ => panic!("Non-boolean provided for Transfers -> Verify option in VaraProxy plugin"),
+    }
+}
+
+// "VaraProxy" -> "Transfers" -> "JobPollIntervalSeconds". Default: 10 seconds.
+pub fn get_job_poll_interval() -> Duration {
+    let c = get_config();
+    Duration::from_secs(
+        c["VaraProxy"]["Transfers"]["JobPollIntervalSeconds"]
+            .as_u64()
+            .unwrap_or(10),
+    )
+}
+
+// How many times a transfer job is submitted (including the first submission)
+// before giving up. "VaraProxy" -> "Transfers" -> "MaxJobSubmissions".
+// Default: 3.
+pub fn get_max_job_submissions() -> u32 {
+    let c = get_config();
+    c["VaraProxy"]["Transfers"]["MaxJobSubmissions"]
+        .as_u64()
+        .unwrap_or(3) as u32
+}
+
+// "VaraProxy" -> "Transfers" -> "Retry", see `orthanc::retries`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    #[serde(rename = "InitialDelaySeconds")]
+    pub initial_delay_seconds: u64,
+    #[serde(rename = "Multiplier")]
+    pub multiplier: f64,
+    #[serde(rename = "MaxDelaySeconds")]
+    pub max_delay_seconds: u64,
+    #[serde(rename = "MaxAttempts")]
+    pub max_attempts: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            initial_delay_seconds: 30,
+            multiplier: 2.0,
+            max_delay_seconds: 3600,
+            max_attempts: 5,
+        }
+    }
+}
+
+pub fn get_retry_config() -> RetryConfig {
+    let c = get_config();
+    let retry = &c["VaraProxy"]["Transfers"]["Retry"];
+    if retry.is_null() {
+        return RetryConfig::default();
+    }
+    json::from_value(retry.clone())
+        .expect("Invalid \"VaraProxy\" -> \"Transfers\" -> \"Retry\" option in VaraProxy plugin")
+}
+
+// "VaraProxy" -> "Retention", see `orthanc::retention`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    #[serde(rename = "Enable")]
+    pub enable: bool,
+    // Studies are kept forever (unless evicted) if not set.
+    #[serde(rename = "DeleteAfterHours")]
+    pub delete_after_hours: Option<u64>,
+    // The destinations a study must be delivered to before it is deleted,
+    // among those it is routed to. All of them if not set.
+    #[serde(rename = "RequiredDestinations")]
+    pub required_destinations: Option<Vec<String>>,
+    // Percentage of the disk holding "StorageDirectory" above which the
+    // oldest delivered studies are evicted. No eviction if not set.
+    #[serde(rename = "HighWatermarkPercent")]
+    pub high_watermark_percent: Option<f64>,
+    // Only log the studies that would be deleted.
+    #[serde(rename = "DryRun")]
+    pub dry_run: bool,
+    #[serde(rename = "IntervalSeconds")]
+    pub interval_seconds: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            enable: false,
+            delete_after_hours: None,
+            required_destinations: None,
+            high_watermark_percent: None,
+            dry_run: false,
+            interval_seconds: 600,
+        }
+    }
+}
+
+pub fn get_retention_config() -> RetentionConfig {
+    let c = get_config();
+    let retention = &c["VaraProxy"]["Retention"];
+    if retention.is_null() {
+        return RetentionConfig::default();
+    }
+    json::from_value(retention.clone())
+        .expect("Invalid \"VaraProxy\" -> \"Retention\" option in VaraProxy plugin")
+}
+
+// "VaraProxy" -> "Pull", see `orthanc::pull`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PullConfig {
+    #[serde(rename = "Enable")]
+    pub enable: bool,
+    // Identifier of the peer in "OrthancPeers" studies are pulled from.
+    #[serde(rename = "Peer")]
+    pub peer: String,
+    #[serde(rename = "IntervalSeconds")]
+    pub interval_seconds: u64,
+    // Only pull the studies of the last "LookbackDays" days.
+    #[serde(rename = "LookbackDays")]
+    pub lookback_days: Option<u64>,
+    // Only pull the studies with one of these modalities.
+    #[serde(rename = "Modalities")]
+    pub modalities: Vec<String>,
+    // Only pull the studies of the patients on the worklist.
+    #[serde(rename = "WorklistPatients")]
+    pub worklist_patients: bool,
+    // Identifier of this Orthanc in the "OrthancPeers" of the peer. If set,
+    // the peer is asked to send studies, otherwise they are copied instance
+    // by instance.
+    #[serde(rename = "LocalPeerOnRemote")]
+    pub local_peer_on_remote: Option<String>,
+    // Pulled studies are deleted "RetentionHours" after they were pulled,
+    // and the oldest ones beyond "MaxStudies".
+    #[serde(rename = "RetentionHours")]
+    pub retention_hours: Option<u64>,
+    #[serde(rename = "MaxStudies")]
+    pub max_studies: Option<usize>,
+}
+
+impl Default for PullConfig {
+    fn default() -> Self {
+        PullConfig {
+            enable: false,
+            peer: String::new(),
+            interval_seconds: 3600,
+            lookback_days: None,
+            modalities: vec![],
+            worklist_patients: false,
+            local_peer_on_remote: None,
+            retention_hours: None,
+            max_studies: None,
+        }
+    }
+}
+
+pub fn get_pull_config() -> PullConfig {
+    let c = get_config();
+    let pull = &c["VaraProxy"]["Pull"];
+    if pull.is_null() {
+        return PullConfig::default();
+    }
+    json::from_value(pull.clone())
+        .expect("Invalid \"VaraProxy\" -> \"Pull\" option in VaraProxy plugin")
+}
+
+// "VaraProxy" -> "Prefetch", see `orthanc::prefetch`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PrefetchConfig {
+    #[serde(rename = "Enable")]
+    pub enable: bool,
+    // Identifier of the peer in "OrthancPeers" priors are pulled from.
+    #[serde(rename = "Peer")]
+    pub peer: String,
+    // Only prefetch the priors with one of these modalities.
+    #[serde(rename = "Modalities")]
+    pub modalities: Vec<String>,
+    // Only prefetch the priors of the last "MaxAgeDays" days.
+    #[serde(rename = "MaxAgeDays")]
+    pub max_age_days: Option<u64>,
+    // See "VaraProxy" -> "Pull" -> "LocalPeerOnRemote".
+    #[serde(rename = "LocalPeerOnRemote")]
+    pub local_peer_on_remote: Option<String>,
+    // How long before the priors of a patient are looked for again.
+    #[serde(rename = "RecheckHours")]
+    pub recheck_hours: u64,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        PrefetchConfig {
+            enable: false,
+            peer: String::new(),
+            modalities: vec![],
+            max_age_days: None,
+            local_peer_on_remote: None,
+            recheck_hours: 12,
+        }
+    }
+}
+
+pub fn get_prefetch_config() -> PrefetchConfig {
+    let c = get_config();
+    let prefetch = &c["VaraProxy"]["Prefetch"];
+    if prefetch.is_null() {
+        return PrefetchConfig::default();
+    }
+    json::from_value(prefetch.clone())
+        .expect("Invalid \"VaraProxy\" -> \"Prefetch\" option in VaraProxy plugin")
+}
+
 //
 // The order of operations in this function is really important. If not done
 // correctly, the plugin will deadlock Orthanc. These deadlocks will happen
@@ -0,0 +1,189 @@
+// Prefetches the prior studies of the patients on the modality worklist, as
+// configured in "VaraProxy" -> "Prefetch":
+//
+//   {"Enable": true, "Peer": "central", "Modalities": ["CT", "MR"],
+//    "MaxAgeDays": 1825, "LocalPeerOnRemote": "buffer", "RecheckHours": 12}
+//
+// The patients of the answered worklist items are queued by
+// `on_worklist_callback`, and a worker looks for their priors on the peer
+// (filtered by modality and age) and pulls the missing ones (see `pull`), so
+// that they are local before the exam. Prefetched priors are pulled studies:
+// they are not forwarded, and they follow the retention of
+// "VaraProxy" -> "Pull".
+//
+// The priors of a patient are looked for again "RecheckHours" after they
+// last were, the state of every patient is listed by `GET /vara/prefetch`.
+
+use super::plugin;
+use super::plugin::PrefetchConfig;
+use super::pull;
+use crate::dates;
+
+use serde::Serialize;
+use serde_json as json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// How often the queued patients are looked at.
+const PREFETCH_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Patients not seen on the worklist for this long are forgotten.
+const PREFETCH_HISTORY_SECONDS: u64 = 7 * 86400;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PatientPrefetch {
+    #[serde(rename = "PatientID")]
+    pub patient_id: String,
+    // "Pending", "Done" or "Failed".
+    #[serde(rename = "State")]
+    pub state: String,
+    // The priors pulled for the patient so far.
+    #[serde(rename = "Studies")]
+    pub studies: Vec<String>,
+    // Seconds since the UNIX epoch.
+    #[serde(rename = "CheckedAt")]
+    pub checked_at: u64,
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// By patient ID.
+static PREFETCHES: Mutex<BTreeMap<String, PatientPrefetch>> = Mutex::new(BTreeMap::new());
+
+// Queues the patients whose priors were not looked for recently.
+pub fn request(patient_ids: Vec<String>) {
+    let config = plugin::get_prefetch_config();
+    if !config.enable {
+        return;
+    }
+    let now = dates::now();
+    let mut prefetches = PREFETCHES.lock().unwrap();
+    for patient_id in patient_ids {
+        let prefetch = prefetches
+            .entry(patient_id.clone())
+            .or_insert_with(|| PatientPrefetch {
+                patient_id,
+                state: String::from("Failed"),
+                studies: vec![],
+                checked_at: now,
+                error: None,
+            });
+        let due = match prefetch.state.as_str() {
+            "Pending" => false,
+            "Failed" => true,
+            _ => prefetch.checked_at + config.recheck_hours * 3600 <= now,
+        };
+        if due {
+            prefetch.state = String::from("Pending");
+        }
+    }
+    prefetches.retain(|_, prefetch| {
+        prefetch.state == "Pending" || prefetch.checked_at + PREFETCH_HISTORY_SECONDS > now
+    });
+}
+
+pub fn get_prefetches() -> Vec<PatientPrefetch> {
+    PREFETCHES.lock().unwrap().values().cloned().collect()
+}
+
+pub fn start_worker() {
+    // The worker runs for the whole lifetime of the plugin, it is not taken
+    // from the plugin's thread pool so as to not starve it.
+    thread::spawn(|| loop {
+        thread::sleep(PREFETCH_POLL_INTERVAL);
+        prefetch_pending_patients();
+    });
+}
+
+fn prefetch_pending_patients() {
+    let pending: Vec<String> = PREFETCHES
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|prefetch| prefetch.state == "Pending")
+        .map(|prefetch| prefetch.patient_id.clone())
+        .collect();
+    if pending.is_empty() {
+        return;
+    }
+
+    let config = plugin::get_prefetch_config();
+    for patient_id in pending {
+        let result = prefetch_priors(&config, &patient_id);
+        let mut prefetches = PREFETCHES.lock().unwrap();
+        let prefetch = match prefetches.get_mut(&patient_id) {
+            Some(prefetch) => prefetch,
+            None => continue,
+        };
+        prefetch.checked_at = dates::now();
+        match result {
+            Ok(studies) => {
+                prefetch.state = String::from("Done");
+                prefetch.studies.extend(studies);
+                prefetch.error = None;
+            }
+            Err(error) => {
+                plugin::error(&format!(
+                    "Failed to prefetch the priors of patient {}: {:?}",
+                    patient_id, error
+                ));
+                prefetch.state = String::from("Failed");
+                prefetch.error = Some(error.to_string());
+            }
+        }
+    }
+}
+
+// Pulls the priors of the patient that are missing locally, and returns the
+// IDs of those that were pulled.
+fn prefetch_priors(
+    config: &PrefetchConfig,
+    patient_id: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    // Would match the studies of many patients.
+    if !pull::is_single_patient_id(patient_id) {
+        return Err(format!("Invalid PatientID {:?}", patient_id).into());
+    }
+    let peer_orthanc = super::get_peer_client(&config.peer)
+        .ok_or_else(|| format!("Unknown peer {}", config.peer))?;
+    let local_orthanc = super::get_local_client();
+
+    let mut query = json::Map::new();
+    query.insert(String::from("PatientID"), json::json!(patient_id));
+    if let Some(max_age_days) = config.max_age_days {
+        query.insert(
+            String::from("StudyDate"),
+            json::json!(format!("{}-", dates::date_days_ago(max_age_days))),
+        );
+    }
+    if !config.modalities.is_empty() {
+        query.insert(
+            String::from("ModalitiesInStudy"),
+            json::json!(config.modalities.join("\\")),
+        );
+    }
+
+    let mut pulled_studies = vec![];
+    for study_id in peer_orthanc.find_studies(&json::Value::Object(query))? {
+        if pull::pull_study(
+            &local_orthanc,
+            &config.peer,
+            &peer_orthanc,
+            config.local_peer_on_remote.as_deref(),
+            &study_id,
+        )? {
+            pulled_studies.push(study_id);
+        }
+    }
+    if !pulled_studies.is_empty() {
+        plugin::info(&format!(
+            "Prefetched {} priors of patient {}",
+            pulled_studies.len(),
+            patient_id
+        ));
+    }
+    Ok(pulled_studies)
+}
@@ -0,0 +1,432 @@
+// Pulls studies from a peer into the local Orthanc, as configured in
+// "VaraProxy" -> "Pull":
+//
+//   {"Enable": true, "Peer": "central", "IntervalSeconds": 3600,
+//    "LookbackDays": 7, "Modalities": ["CT", "MR"], "WorklistPatients": true,
+//    "LocalPeerOnRemote": "buffer", "RetentionHours": 72, "MaxStudies": 500}
+//
+// Every "IntervalSeconds", the studies of the peer matching all the criteria
+// (see `/tools/find`) that are missing locally are pulled: the peer sends them
+// if it knows this Orthanc as "LocalPeerOnRemote", otherwise they are copied
+// instance by instance. At least one criterion must be set, so that the whole
+// peer is never pulled. "WorklistPatients" are the patients of the worklist
+// items answered during the last day.
+//
+// Pulled studies are recorded in `{StorageDirectory}/vara/pulled`, along with
+// their "LastUpdate" once pulled. They are not forwarded to the destinations
+// (see `routing`), and they are deleted "RetentionHours" after they were
+// pulled, and beyond "MaxStudies" (oldest first). The same goes for the
+// priors pulled by `prefetch`. Studies that changed since they were pulled
+// (e.g. a report or key images were added locally) are forwarded and kept
+// like any other study.
+
+use super::http::StoreTarget;
+use super::plugin;
+use super::plugin::PullConfig;
+use super::OrthancClient;
+use crate::cache;
+use crate::dates;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json as json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// How long the patients of answered worklist items are remembered.
+const WORKLIST_PATIENT_LIFETIME_SECONDS: u64 = 86400;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PulledStudy {
+    #[serde(rename = "Peer")]
+    peer: String,
+    // Seconds since the UNIX epoch.
+    #[serde(rename = "PulledAt")]
+    pulled_at: u64,
+    // Studies that failed to be pulled completely are pulled again.
+    #[serde(rename = "Complete")]
+    complete: bool,
+    // "LastUpdate" of the local study once pulled, missing in records written
+    // by earlier versions of the plugin.
+    #[serde(rename = "LastUpdate", default)]
+    last_update: Option<String>,
+}
+
+// When each patient was last seen on the worklist.
+static WORKLIST_PATIENTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn remember_worklist_patients(patient_ids: Vec<String>) {
+    let now = dates::now();
+    let mut patients = WORKLIST_PATIENTS.lock().unwrap();
+    for patient_id in patient_ids {
+        patients.insert(patient_id, now);
+    }
+    patients.retain(|_, seen_at| *seen_at + WORKLIST_PATIENT_LIFETIME_SECONDS > now);
+}
+
+// Whether `patient_id` names a single patient: an empty PatientID, or one with
+// the "*" or "?" wildcards, matches many patients in `/tools/find`.
+pub fn is_single_patient_id(patient_id: &str) -> bool {
+    !patient_id.trim().is_empty() && !patient_id.contains(['*', '?'])
+}
+
+// Whether the study was pulled (or is being pulled) and did not change
+// since.
+pub fn is_pulled(local_orthanc: &OrthancClient, study_id: &str) -> super::http::Result<bool> {
+    let pulled_study = match load_pulled_study(study_id) {
+        Ok(pulled_study) => pulled_study,
+        Err(_) => return Ok(false),
+    };
+    let last_update = match pulled_study.last_update {
+        Some(last_update) if pulled_study.complete => last_update,
+        _ => return Ok(true),
+    };
+    Ok(local_orthanc.get_study(study_id)?.last_update == last_update)
+}
+
+pub fn start_worker() {
+    // The worker runs for the whole lifetime of the plugin, it is not taken
+    // from the plugin's thread pool so as to not starve it.
+    thread::spawn(|| loop {
+        let config = plugin::get_pull_config();
+        thread::sleep(Duration::from_secs(config.interval_seconds));
+        if config.enable {
+            if let Err(error) = pull_studies(&config) {
+                plugin::error(&format!("Failed to pull studies: {:?}", error));
+            }
+        }
+        // Also applies to the priors of `prefetch`.
+        if let Err(error) = apply_retention(&config) {
+            plugin::error(&format!(
+                "Failed to delete expired pulled studies: {:?}",
+                error
+            ));
+        }
+    });
+}
+
+// Pulls a study from the peer, unless it is already local. Returns whether
+// the study was pulled.
+pub fn pull_study(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    peer_orthanc: &OrthancClient,
+    local_peer_on_remote: Option<&str>,
+    study_id: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let pulled_study = load_pulled_study(study_id).ok();
+    if pulled_study
+        .as_ref()
+        .is_some_and(|pulled_study| pulled_study.complete)
+        || (pulled_study.is_none() && local_orthanc.has_resource("studies", study_id)?)
+    {
+        return Ok(false);
+    }
+
+    // Recorded first, so that the instances are not forwarded as they
+    // arrive.
+    let mut pulled_study = PulledStudy {
+        peer: peer_identifier.to_string(),
+        pulled_at: dates::now(),
+        complete: false,
+        last_update: None,
+    };
+    save_pulled_study(study_id, &pulled_study)?;
+    match local_peer_on_remote {
+        Some(local_peer_on_remote) => peer_orthanc.transfer_entities(
+            &StoreTarget::Peer(local_peer_on_remote.to_string()),
+            vec![study_id.to_string()],
+        )?,
+        None => {
+            for instance_id in peer_orthanc.get_study_instance_ids(study_id)? {
+                local_orthanc.upload_instance(peer_orthanc.get_instance_file(&instance_id)?)?;
+            }
+        }
+    }
+    pulled_study.complete = true;
+    pulled_study.last_update = Some(local_orthanc.get_study(study_id)?.last_update);
+    save_pulled_study(study_id, &pulled_study)?;
+    plugin::info(&format!(
+        "Pulled study {} from {}",
+        study_id, peer_identifier
+    ));
+    Ok(true)
+}
+
+fn pull_studies(config: &PullConfig) -> Result<(), Box<dyn Error>> {
+    let peer_orthanc = match super::get_peer_client(&config.peer) {
+        Some(peer_orthanc) => peer_orthanc,
+        None => return Ok(()),
+    };
+    let local_orthanc = super::get_local_client();
+
+    let mut query = json::Map::new();
+    if let Some(lookback_days) = config.lookback_days {
+        query.insert(
+            String::from("StudyDate"),
+            json::json!(format!("{}-", dates::date_days_ago(lookback_days))),
+        );
+    }
+    if !config.modalities.is_empty() {
+        query.insert(
+            String::from("ModalitiesInStudy"),
+            json::json!(config.modalities.join("\\")),
+        );
+    }
+    let queries = if config.worklist_patients {
+        let patient_ids: Vec<String> = WORKLIST_PATIENTS
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|patient_id| is_single_patient_id(patient_id))
+            .cloned()
+            .collect();
+        patient_ids
+            .into_iter()
+            .map(|patient_id| {
+                let mut query = query.clone();
+                query.insert(String::from("PatientID"), json::json!(patient_id));
+                query
+            })
+            .collect()
+    } else if query.is_empty() {
+        plugin::warning(
+            "Not pulling studies: no criterion configured in \"VaraProxy\" -> \"Pull\"",
+        );
+        return Ok(());
+    } else {
+        vec![query]
+    };
+
+    for query in queries {
+        for study_id in peer_orthanc.find_studies(&json::Value::Object(query))? {
+            // A failing study does not prevent the others from being pulled.
+            if let Err(error) = pull_study(
+                &local_orthanc,
+                &config.peer,
+                &peer_orthanc,
+                config.local_peer_on_remote.as_deref(),
+                &study_id,
+            ) {
+                plugin::error(&format!(
+                    "Failed to pull study {} from {}: {:?}",
+                    study_id, config.peer, error
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Deletes the expired pulled studies. Failures are logged, so that the other
+// studies are still looked at.
+fn apply_retention(config: &PullConfig) -> Result<(), Box<dyn Error>> {
+    let local_orthanc = super::get_local_client();
+    let mut pulled_studies = vec![];
+    for study_id in list_pulled_studies()? {
+        match local_orthanc.has_resource("studies", &study_id) {
+            Ok(true) => (),
+            Ok(false) => {
+                remove_pulled_study(&study_id);
+                continue;
+            }
+            Err(error) => {
+                plugin::error(&format!(
+                    "Failed to look up pulled study {}: {:?}",
+                    study_id, error
+                ));
+                continue;
+            }
+        }
+        // From now on, the study is forwarded and kept like a local one.
+        match is_pulled(&local_orthanc, &study_id) {
+            Ok(true) => (),
+            Ok(false) => {
+                plugin::info(&format!(
+                    "Pulled study {} changed locally, it is no longer deleted",
+                    study_id
+                ));
+                remove_pulled_study(&study_id);
+                continue;
+            }
+            Err(error) => {
+                plugin::error(&format!(
+                    "Failed to get pulled study {}: {:?}",
+                    study_id, error
+                ));
+                continue;
+            }
+        }
+        match load_pulled_study(&study_id) {
+            Ok(pulled_study) => pulled_studies.push((pulled_study.pulled_at, study_id)),
+            Err(error) => plugin::error(&format!(
+                "Failed to load the record of pulled study {}: {:?}",
+                study_id, error
+            )),
+        }
+    }
+
+    for study_id in select_expired_studies(config, pulled_studies, dates::now()) {
+        if let Err(error) = local_orthanc.delete_resource("studies", &study_id) {
+            plugin::error(&format!(
+                "Failed to delete pulled study {}: {:?}",
+                study_id, error
+            ));
+            continue;
+        }
+        remove_pulled_study(&study_id);
+        plugin::info(&format!("Deleted pulled study {}", study_id));
+    }
+    Ok(())
+}
+
+// Returns the studies among `pulled_studies` (when they were pulled, and
+// their ID) pulled more than "RetentionHours" before `now`, and the oldest
+// ones beyond "MaxStudies".
+fn select_expired_studies(
+    config: &PullConfig,
+    mut pulled_studies: Vec<(u64, String)>,
+    now: u64,
+) -> Vec<String> {
+    // Oldest first.
+    pulled_studies.sort();
+    let excess = config.max_studies.map_or(0, |max_studies| {
+        pulled_studies.len().saturating_sub(max_studies)
+    });
+    pulled_studies
+        .into_iter()
+        .enumerate()
+        .filter(|(index, (pulled_at, _))| {
+            *index < excess
+                || config
+                    .retention_hours
+                    .is_some_and(|hours| pulled_at + hours * 3600 <= now)
+        })
+        .map(|(_, (_, study_id))| study_id)
+        .collect()
+}
+
+fn list_pulled_studies() -> io::Result<Vec<String>> {
+    let directory = get_pulled_directory();
+    if !directory.exists() {
+        return Ok(vec![]);
+    }
+    Ok(fs::read_dir(&directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .filter_map(|path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .collect())
+}
+
+fn load_pulled_study(study_id: &str) -> io::Result<PulledStudy> {
+    Ok(json::from_str(&cache::read(&get_pulled_path(study_id))?)?)
+}
+
+fn save_pulled_study(study_id: &str, pulled_study: &PulledStudy) -> io::Result<()> {
+    fs::create_dir_all(get_pulled_directory())?;
+    cache::write(&json::to_string(pulled_study)?, &get_pulled_path(study_id))
+}
+
+fn remove_pulled_study(study_id: &str) {
+    match fs::remove_file(get_pulled_path(study_id)) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => plugin::error(&format!(
+            "Failed to remove the record of pulled study {}: {:?}",
+            study_id, error
+        )),
+        _ => (),
+    }
+}
+
+fn get_pulled_path(study_id: &str) -> PathBuf {
+    get_pulled_directory().join(format!("{}.json", study_id))
+}
+
+fn get_pulled_directory() -> PathBuf {
+    plugin::get_storage_directory().join("vara").join("pulled")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config(retention_hours: Option<u64>, max_studies: Option<usize>) -> PullConfig {
+        PullConfig {
+            retention_hours,
+            max_studies,
+            ..Default::default()
+        }
+    }
+
+    fn get_pulled_studies(pulled_studies: &[(u64, &str)]) -> Vec<(u64, String)> {
+        pulled_studies
+            .iter()
+            .map(|(pulled_at, study_id)| (*pulled_at, study_id.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn deletes_studies_after_the_retention_period() {
+        let config = get_config(Some(1), None);
+        let pulled_studies =
+            get_pulled_studies(&[(10_000, "recent"), (6_400, "old"), (5_000, "older")]);
+        assert_eq!(
+            select_expired_studies(&config, pulled_studies, 10_400),
+            vec!["older", "old"]
+        );
+    }
+
+    #[test]
+    fn deletes_the_oldest_studies_beyond_the_maximum() {
+        let config = get_config(None, Some(2));
+        let pulled_studies = get_pulled_studies(&[(300, "c"), (100, "a"), (400, "d"), (200, "b")]);
+        assert_eq!(
+            select_expired_studies(&config, pulled_studies, 1_000_000),
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn combines_the_retention_period_and_the_maximum() {
+        let config = get_config(Some(1), Some(2));
+        let pulled_studies = get_pulled_studies(&[(100, "a"), (9_000, "b"), (9_500, "c")]);
+        // "a" is both expired and beyond the maximum.
+        assert_eq!(
+            select_expired_studies(&config, pulled_studies.clone(), 9_600),
+            vec!["a"]
+        );
+        assert_eq!(
+            select_expired_studies(&config, pulled_studies, 12_700),
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn keeps_studies_without_limits() {
+        let config = get_config(None, None);
+        let pulled_studies = get_pulled_studies(&[(0, "a"), (1, "b")]);
+        assert!(select_expired_studies(&config, pulled_studies, u64::MAX / 2).is_empty());
+    }
+
+    #[test]
+    fn rejects_wildcard_patient_ids() {
+        assert!(is_single_patient_id("12345"));
+        assert!(is_single_patient_id("DOE-1"));
+        assert!(!is_single_patient_id(""));
+        assert!(!is_single_patient_id("  "));
+        assert!(!is_single_patient_id("*"));
+        assert!(!is_single_patient_id("123*"));
+        assert!(!is_single_patient_id("12?45"));
+    }
+}
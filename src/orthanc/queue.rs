@@ -0,0 +1,169 @@
+// On-disk queue of the transfers to the peer, so that pending and in-flight
+// transfers survive a restart of Orthanc. Every transfer is a JSON file in
+// `{StorageDirectory}/vara/transfer-queue`, written when the transfer is
+// requested, updated whenever it fails, and removed once the peer confirmed
+// it (see `retries` and `jobs`).
+//
+// Stable studies are queued before they are routed (see `enqueue_routing`),
+// so that they are not lost when routing fails or Orthanc stops first.
+
+use super::plugin;
+use crate::cache;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json as json;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedTransfer {
+    // Also the name of the file holding the transfer.
+    #[serde(rename = "ID")]
+    pub id: String,
+    // Name of the destination, see `plugin::Destination`.
+    #[serde(rename = "Peer", default = "legacy_peer")]
+    pub peer: String,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<String>,
+    // "studies" or "instances", empty for transfers queued before it was
+    // recorded (see `retries::attempt`).
+    #[serde(rename = "ResourceType", default)]
+    pub resource_type: String,
+    #[serde(rename = "Attempts", default)]
+    pub attempts: u32,
+    // Seconds since the UNIX epoch.
+    #[serde(rename = "NextAttemptAt", default)]
+    pub next_attempt_at: u64,
+    #[serde(rename = "LastError", default)]
+    pub last_error: String,
+    #[serde(rename = "DeadLetter", default)]
+    pub dead_letter: bool,
+    // Transfers of the sync, held back by the transfer window of their
+    // destination (see `schedule`).
+    #[serde(rename = "Backlog", default)]
+    pub backlog: bool,
+    // Studies still to be routed, see `enqueue_routing`. `peer` is empty.
+    #[serde(rename = "Unrouted", default)]
+    pub unrouted: bool,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Before studies could be forwarded to several peers, queued transfers did not
+// name their peer: they went to "VaraProxy" -> "Peer", or to the first of
+// "VaraProxy" -> "Peers" once the configuration moved to several peers.
+fn legacy_peer() -> String {
+    let c = plugin::get_config();
+    match c["VaraProxy"]["Peer"].as_str() {
+        Some(peer_identifier) => peer_identifier.to_string(),
+        None => plugin::get_peer_identifiers()
+            .into_iter()
+            .next()
+            .unwrap_or_default(),
+    }
+}
+
+// Creates a transfer of `resources` ("studies" or "instances", see
+// `resource_type`) to `peer`, without writing it to the queue.
+pub fn new_transfer(peer: &str, resource_type: &str, resources: Vec<String>) -> QueuedTransfer {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_nanos();
+    QueuedTransfer {
+        id: format!("{}-{}", nanos, NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+        peer: peer.to_string(),
+        resources,
+        resource_type: resource_type.to_string(),
+        attempts: 0,
+        next_attempt_at: 0,
+        last_error: String::new(),
+        dead_letter: false,
+        backlog: false,
+        unrouted: false,
+    }
+}
+
+// Creates a transfer of `resources` to `peer` and writes it to the queue.
+pub fn enqueue(
+    peer: &str,
+    resource_type: &str,
+    resources: Vec<String>,
+) -> io::Result<QueuedTransfer> {
+    let transfer = new_transfer(peer, resource_type, resources);
+    save(&transfer)?;
+    Ok(transfer)
+}
+
+// Creates the routing of a stable study and writes it to the queue. It is
+// replaced by a transfer per destination once the study is routed (see
+// `retries::attempt`).
+pub fn enqueue_routing(study_id: &str) -> io::Result<QueuedTransfer> {
+    let mut transfer = new_transfer("", "studies", vec![study_id.to_string()]);
+    transfer.unrouted = true;
+    save(&transfer)?;
+    Ok(transfer)
+}
+
+pub fn save(transfer: &QueuedTransfer) -> io::Result<()> {
+    let directory = get_queue_directory();
+    fs::create_dir_all(&directory)?;
+    cache::write(
+        &json::to_string(transfer)?,
+        &directory.join(format!("{}.json", transfer.id)),
+    )
+}
+
+pub fn remove(id: &str) -> io::Result<()> {
+    match fs::remove_file(get_queue_directory().join(format!("{}.json", id))) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+// Returns every queued transfer, oldest first. Unreadable files are skipped
+// with a warning.
+pub fn load() -> io::Result<Vec<QueuedTransfer>> {
+    let directory = get_queue_directory();
+    if !directory.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .collect();
+    // IDs start with the creation time.
+    paths.sort();
+
+    let mut transfers = vec![];
+    for path in paths {
+        let transfer =
+            cache::read(&path).and_then(|text| Ok(json::from_str::<QueuedTransfer>(&text)?));
+        match transfer {
+            Ok(transfer) => transfers.push(transfer),
+            Err(error) => plugin::warning(&format!(
+                "Skipping unreadable queued transfer {}: {:?}",
+                path.display(),
+                error
+            )),
+        }
+    }
+    Ok(transfers)
+}
+
+fn get_queue_directory() -> PathBuf {
+    plugin::get_storage_directory()
+        .join("vara")
+        .join("transfer-queue")
+}
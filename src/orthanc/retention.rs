@@ -0,0 +1,494 @@
+// Deletes local studies once they were delivered to their destinations, as
+// configured in "VaraProxy" -> "Retention":
+//
+//   {"Enable": true, "DeleteAfterHours": 24, "RequiredDestinations": ["archive"],
+//    "HighWatermarkPercent": 90, "DryRun": false, "IntervalSeconds": 600}
+//
+// A study is delivered once it was transferred (and verified, see `verify`)
+// to every destination it is routed to (see `routing`), or only to those of
+// "RequiredDestinations" if set. Delivered studies are deleted
+// "DeleteAfterHours" after their last delivery and, when the disk holding
+// "StorageDirectory" is used above "HighWatermarkPercent", evicted oldest
+// delivery first until it is not anymore. With "DryRun", the studies that
+// would be deleted are only logged.
+//
+// Deliveries are recorded in `{StorageDirectory}/vara/deliveries`, one JSON
+// file per study, along with the "LastUpdate" of the study before it was
+// sent: a study that changed since (e.g. received new instances) is not
+// delivered until it is sent again. The deliveries to DICOM modalities and
+// STOW-RS services are recorded even with retention disabled, as the sync
+// relies on them (see `is_delivered`). Studies that are queued or being
+// transferred (see `retries::get_attempted_resources`), and studies routed to
+// no destination, are never deleted.
+// Neither are studies that changed while the policy was applied: their
+// "LastUpdate" is checked again right before deleting them.
+
+use super::http::Resource;
+use super::plugin;
+use super::plugin::RetentionConfig;
+use super::routing::Router;
+use super::OrthancClient;
+use crate::cache;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json as json;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// The "LastUpdate" of a study when its transfer started.
+#[derive(Debug, Clone)]
+pub struct StudyVersion {
+    pub study: String,
+    pub last_update: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Delivery {
+    // Seconds since the UNIX epoch.
+    #[serde(rename = "DeliveredAt")]
+    delivered_at: u64,
+    #[serde(rename = "LastUpdate")]
+    last_update: String,
+}
+
+// The deliveries of a study, by destination.
+type Deliveries = HashMap<String, Delivery>;
+
+// Deliveries of the same study to several destinations are recorded
+// concurrently.
+static DELIVERIES_LOCK: Mutex<()> = Mutex::new(());
+
+// Returns the versions of the studies of `resource_ids`, to be recorded once
+// they are delivered to the destination (transfers of instances deliver no
+// study). Nothing is recorded for Orthanc
+// peers if retention is disabled: the sync lists their studies, but only
+// knows those of the other destinations by their deliveries (see
+// `is_delivered`).
+pub fn get_study_versions(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    resource_type: &str,
+    resource_ids: &[String],
+) -> super::http::Result<Vec<StudyVersion>> {
+    if resource_type != "studies" {
+        return Ok(vec![]);
+    }
+    if !plugin::get_retention_config().enable
+        && plugin::get_destination(peer_identifier).kind == plugin::DestinationType::Peer
+    {
+        return Ok(vec![]);
+    }
+    let mut study_versions = vec![];
+    for study_id in resource_ids {
+        study_versions.push(StudyVersion {
+            study: study_id.clone(),
+            last_update: local_orthanc.get_study(study_id)?.last_update,
+        });
+    }
+    Ok(study_versions)
+}
+
+pub fn record_delivery(peer_identifier: &str, study_versions: &[StudyVersion]) {
+    let _guard = DELIVERIES_LOCK.lock().unwrap();
+    for study_version in study_versions {
+        let mut deliveries = load_deliveries(&study_version.study).unwrap_or_default();
+        deliveries.insert(
+            peer_identifier.to_string(),
+            Delivery {
+                delivered_at: now(),
+                last_update: study_version.last_update.clone(),
+            },
+        );
+        if let Err(error) = save_deliveries(&study_version.study, &deliveries) {
+            plugin::error(&format!(
+                "Failed to record the delivery of study {} to {}: {:?}",
+                study_version.study, peer_identifier, error
+            ));
+        }
+    }
+}
+
+// Whether the current version of the study was delivered to the destination.
+pub fn is_delivered(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    study_id: &str,
+) -> super::http::Result<bool> {
+    let delivery = match load_deliveries(study_id) {
+        Ok(mut deliveries) => match deliveries.remove(peer_identifier) {
+            Some(delivery) => delivery,
+            None => return Ok(false),
+        },
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => {
+            plugin::error(&format!(
+                "Failed to load the deliveries of study {}: {:?}",
+                study_id, error
+            ));
+            return Ok(false);
+        }
+    };
+    Ok(delivery.last_update == local_orthanc.get_study(study_id)?.last_update)
+}
+
+// Applies the retention policy every "IntervalSeconds".
+pub fn start_worker() {
+    // The worker runs for the whole lifetime of the plugin, it is not taken
+    // from the plugin's thread pool so as to not starve it.
+    thread::spawn(|| loop {
+        let config = plugin::get_retention_config();
+        thread::sleep(Duration::from_secs(config.interval_seconds));
+        if !config.enable {
+            continue;
+        }
+        if let Err(error) = apply(&config) {
+            plugin::error(&format!(
+                "Failed to apply the retention policy: {:?}",
+                error
+            ));
+        }
+    });
+}
+
+fn apply(config: &RetentionConfig) -> Result<(), Box<dyn Error>> {
+    let local_orthanc = super::get_local_client();
+    let mut router = Router::new(super::get_local_client());
+    let in_transfer: HashSet<String> = super::retries::get_pending()
+        .into_iter()
+        .map(|transfer| transfer.resources)
+        .chain(
+            super::jobs::get_transfer_jobs()
+                .into_iter()
+                .map(|job| job.resources),
+        )
+        .chain([super::retries::get_attempted_resources()])
+        .flatten()
+        .collect();
+
+    // Delivered studies, oldest delivery first.
+    let mut delivered_studies = vec![];
+    for study_id in list_delivered_studies()? {
+        if !local_orthanc.has_resource("studies", &study_id)? {
+            remove_deliveries(&study_id);
+            continue;
+        }
+        if in_transfer.contains(&study_id) {
+            continue;
+        }
+        let deliveries = load_deliveries(&study_id)?;
+        if let Some((delivered_at, last_update)) =
+            get_delivery_time(&local_orthanc, &mut router, config, &study_id, &deliveries)?
+        {
+            delivered_studies.push((delivered_at, study_id, last_update));
+        }
+    }
+    delivered_studies.sort();
+
+    let mut kept_studies = vec![];
+    for (delivered_at, study_id, last_update) in delivered_studies {
+        let expired = config
+            .delete_after_hours
+            .is_some_and(|hours| delivered_at + hours * 3600 <= now());
+        if expired {
+            delete_study(
+                &local_orthanc,
+                config,
+                &study_id,
+                &last_update,
+                "retention period elapsed",
+            );
+        } else {
+            kept_studies.push((study_id, last_update));
+        }
+    }
+
+    let high_watermark_percent = match config.high_watermark_percent {
+        Some(high_watermark_percent) => high_watermark_percent,
+        None => return Ok(()),
+    };
+    let (used, total) = get_disk_usage(&plugin::get_storage_directory())?;
+    let high_watermark = (total as f64 * high_watermark_percent / 100.0) as u64;
+    let mut excess = used.saturating_sub(high_watermark);
+    for (study_id, last_update) in kept_studies {
+        if excess == 0 {
+            break;
+        }
+        let disk_size = match local_orthanc.get_study_disk_size(&study_id) {
+            Ok(disk_size) => disk_size,
+            Err(error) => {
+                plugin::error(&format!(
+                    "Retention: failed to get the disk size of study {}: {:?}",
+                    study_id, error
+                ));
+                continue;
+            }
+        };
+        let deleted = delete_study(
+            &local_orthanc,
+            config,
+            &study_id,
+            &last_update,
+            "disk above high watermark",
+        );
+        if deleted {
+            excess = excess.saturating_sub(disk_size);
+        }
+    }
+    if excess > 0 {
+        plugin::warning(&format!(
+            "Disk still {} bytes above the high watermark, no more delivered studies to evict",
+            excess
+        ));
+    }
+    Ok(())
+}
+
+// When the study was delivered to the last of its required destinations,
+// along with its "LastUpdate", or `None` if its current version was not
+// delivered to all of them.
+fn get_delivery_time(
+    local_orthanc: &OrthancClient,
+    router: &mut Router,
+    config: &RetentionConfig,
+    study_id: &str,
+    deliveries: &Deliveries,
+) -> super::http::Result<Option<(u64, String)>> {
+    let mut destinations = router.get_study_destinations(study_id)?;
+    if let Some(required_destinations) = &config.required_destinations {
+        destinations.retain(|destination| required_destinations.contains(destination));
+    }
+    // Studies kept local are never delivered.
+    if destinations.is_empty() {
+        return Ok(None);
+    }
+
+    let last_update = local_orthanc.get_study(study_id)?.last_update;
+    Ok(get_last_delivery(&destinations, deliveries, &last_update)
+        .map(|delivered_at| (delivered_at, last_update)))
+}
+
+// When the version `last_update` of a study was delivered to the last of the
+// destinations, or `None` if it was not delivered to all of them.
+fn get_last_delivery(
+    destinations: &[String],
+    deliveries: &Deliveries,
+    last_update: &str,
+) -> Option<u64> {
+    let mut delivered_at = 0;
+    for destination in destinations {
+        match deliveries.get(destination) {
+            Some(delivery) if delivery.last_update == last_update => {
+                delivered_at = delivered_at.max(delivery.delivered_at)
+            }
+            _ => return None,
+        }
+    }
+    Some(delivered_at)
+}
+
+// Deletes the study unless it changed since its "LastUpdate" was
+// `last_update`, and returns whether it was (or, with "DryRun", would be)
+// deleted. Failures are logged, so
+// that the other studies are still looked at.
+fn delete_study(
+    local_orthanc: &OrthancClient,
+    config: &RetentionConfig,
+    study_id: &str,
+    last_update: &str,
+    reason: &str,
+) -> bool {
+    match local_orthanc.get_study(study_id) {
+        Ok(study) if !has_changed(&study, last_update) => (),
+        Ok(_) => {
+            plugin::info(&format!(
+                "Retention: keeping study {}, it changed since it was delivered",
+                study_id
+            ));
+            return false;
+        }
+        Err(error) => {
+            plugin::error(&format!(
+                "Retention: failed to get study {}: {:?}",
+                study_id, error
+            ));
+            return false;
+        }
+    }
+
+    if config.dry_run {
+        plugin::info(&format!(
+            "Retention (dry run): would delete study {} ({})",
+            study_id, reason
+        ));
+        return true;
+    }
+    if let Err(error) = local_orthanc.delete_resource("studies", study_id) {
+        plugin::error(&format!(
+            "Retention: failed to delete study {}: {:?}",
+            study_id, error
+        ));
+        return false;
+    }
+    remove_deliveries(study_id);
+    plugin::info(&format!(
+        "Retention: deleted study {} ({})",
+        study_id, reason
+    ));
+    true
+}
+
+// Whether the study received instances since its "LastUpdate" was
+// `last_update`.
+fn has_changed(study: &Resource, last_update: &str) -> bool {
+    study.last_update != last_update
+}
+
+// Returns the used and total sizes, in bytes, of the file system holding
+// `path`.
+fn get_disk_usage(path: &Path) -> io::Result<(u64, u64)> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block_size = stats.f_frsize as u64;
+    let total = stats.f_blocks as u64 * block_size;
+    let free = stats.f_bfree as u64 * block_size;
+    Ok((total.saturating_sub(free), total))
+}
+
+fn list_delivered_studies() -> io::Result<Vec<String>> {
+    let directory = get_deliveries_directory();
+    if !directory.exists() {
+        return Ok(vec![]);
+    }
+    Ok(fs::read_dir(&directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .filter_map(|path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .collect())
+}
+
+fn load_deliveries(study_id: &str) -> io::Result<Deliveries> {
+    let text = cache::read(&get_deliveries_directory().join(format!("{}.json", study_id)))?;
+    Ok(json::from_str(&text)?)
+}
+
+fn save_deliveries(study_id: &str, deliveries: &Deliveries) -> io::Result<()> {
+    let directory = get_deliveries_directory();
+    fs::create_dir_all(&directory)?;
+    cache::write(
+        &json::to_string(deliveries)?,
+        &directory.join(format!("{}.json", study_id)),
+    )
+}
+
+fn remove_deliveries(study_id: &str) {
+    let path = get_deliveries_directory().join(format!("{}.json", study_id));
+    match fs::remove_file(&path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => plugin::error(&format!(
+            "Failed to remove the deliveries of study {}: {:?}",
+            study_id, error
+        )),
+        _ => (),
+    }
+}
+
+fn get_deliveries_directory() -> PathBuf {
+    plugin::get_storage_directory()
+        .join("vara")
+        .join("deliveries")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn get_deliveries(deliveries: &[(&str, u64, &str)]) -> Deliveries {
+        deliveries
+            .iter()
+            .map(|(destination, delivered_at, last_update)| {
+                (
+                    destination.to_string(),
+                    Delivery {
+                        delivered_at: *delivered_at,
+                        last_update: last_update.to_string(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn get_destinations(destinations: &[&str]) -> Vec<String> {
+        destinations
+            .iter()
+            .map(|destination| destination.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn delivers_studies_once_all_destinations_have_them() {
+        let deliveries = get_deliveries(&[("a", 100, "v1"), ("b", 200, "v1")]);
+        assert_eq!(
+            get_last_delivery(&get_destinations(&["a", "b"]), &deliveries, "v1"),
+            Some(200)
+        );
+        assert_eq!(
+            get_last_delivery(&get_destinations(&["a"]), &deliveries, "v1"),
+            Some(100)
+        );
+        assert_eq!(
+            get_last_delivery(&get_destinations(&["a", "c"]), &deliveries, "v1"),
+            None
+        );
+    }
+
+    #[test]
+    fn does_not_deliver_changed_studies() {
+        let deliveries = get_deliveries(&[("a", 100, "v1"), ("b", 200, "v2")]);
+        assert_eq!(
+            get_last_delivery(&get_destinations(&["a", "b"]), &deliveries, "v2"),
+            None
+        );
+        assert_eq!(
+            get_last_delivery(&get_destinations(&["b"]), &deliveries, "v2"),
+            Some(200)
+        );
+    }
+
+    #[test]
+    fn keeps_studies_changed_since_they_were_delivered() {
+        let study: Resource =
+            serde_json::from_value(json!({"ID": "study", "LastUpdate": "20231018T101500"}))
+                .unwrap();
+        assert!(!has_changed(&study, "20231018T101500"));
+        assert!(has_changed(&study, "20231018T090000"));
+    }
+}
@@ -0,0 +1,523 @@
+// Attempts the transfers of the on-disk `queue`, and retries failed ones with
+// an exponential backoff, as configured in
+// "VaraProxy" -> "Transfers" -> "Retry":
+//
+//   {"InitialDelaySeconds": 30, "Multiplier": 2, "MaxDelaySeconds": 3600,
+//    "MaxAttempts": 5}
+//
+// Transfers that failed "MaxAttempts" times (including the first attempt) are
+// moved to a dead-letter list along with their last error. Dead letters are
+// listed by `GET /vara/transfers/dead-letters` and moved back to the retry
+// queue by `POST /vara/transfers/dead-letters/replay`.
+//
+// Due transfers are attempted per destination, so that a destination that is
+// down does not hold back the others.
+//
+// Transfers held back by a transfer window or the upload cap (see
+// `schedule`) are postponed until they may start.
+//
+// Stable studies are queued unrouted (see `queue::enqueue_routing`): their
+// attempts route them, and routing failures are retried like transfers.
+
+use super::plugin;
+use super::queue;
+use super::queue::QueuedTransfer;
+use super::routing::Router;
+use super::schedule;
+use super::OrthancClient;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// How often the retry queue is checked for transfers that are due.
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Queues {
+    pending: Vec<QueuedTransfer>,
+    dead_letters: Vec<QueuedTransfer>,
+}
+
+static QUEUES: Mutex<Queues> = Mutex::new(Queues {
+    pending: vec![],
+    dead_letters: vec![],
+});
+
+// The resources of the attempts in progress, once per attempt, see
+// `get_attempted_resources`.
+static ATTEMPTED_RESOURCES: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+// Destinations whose due transfers are being attempted, see
+// `retry_due_transfers`.
+static BUSY_DESTINATIONS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// Reloads the transfers left in the on-disk queue by a previous run of the
+// plugin. Transfers that were in flight are attempted again, the peer
+// ignores the instances it already has.
+pub fn load() {
+    let transfers = match queue::load() {
+        Ok(transfers) => transfers,
+        Err(error) => {
+            plugin::error(&format!("Failed to load the transfer queue: {:?}", error));
+            return;
+        }
+    };
+    plugin::info(&format!("Loaded {} queued transfers", transfers.len()));
+
+    let mut queues = QUEUES.lock().unwrap();
+    for transfer in transfers {
+        if transfer.dead_letter {
+            queues.dead_letters.push(transfer);
+        } else {
+            queues.pending.push(transfer);
+        }
+    }
+}
+
+// Attempts a queued transfer. It is removed from the queue once the peer
+// confirmed it, either right away or when its job succeeds (see `jobs`).
+pub fn attempt(local_orthanc: &OrthancClient, mut transfer: QueuedTransfer) {
+    let _attempt = Attempt::start(&transfer.resources);
+    if transfer.unrouted {
+        route(transfer);
+        return;
+    }
+
+    // Transfers outside of the window of their destination, or held back by
+    // the upload cap, wait without using an attempt.
+    if let Some(start_at) = schedule::get_start_time(&transfer.peer, transfer.backlog) {
+        plugin::info(&format!(
+            "Transfer of {:?} to {} postponed by {} seconds",
+            transfer.resources,
+            transfer.peer,
+            start_at.saturating_sub(now())
+        ));
+        transfer.next_attempt_at = start_at;
+        save_to_queue(&transfer);
+        QUEUES.lock().unwrap().pending.push(transfer);
+        return;
+    }
+
+    // Transfers queued before their resource type was recorded are studies,
+    // unless the local Orthanc says otherwise.
+    if transfer.resource_type.is_empty() {
+        let is_study = transfer
+            .resources
+            .first()
+            .map(|id| local_orthanc.has_resource("studies", id));
+        transfer.resource_type = match is_study {
+            Some(Ok(false)) => String::from("instances"),
+            _ => String::from("studies"),
+        };
+    }
+
+    transfer.attempts += 1;
+    match super::transfer_to_peer(
+        local_orthanc,
+        &transfer.peer,
+        &transfer.resource_type,
+        transfer.resources.clone(),
+        Some(&transfer.id),
+    ) {
+        Ok(None) => {
+            plugin::info(&format!(
+                "Successfully transferred {:?} to {} (attempt {})",
+                transfer.resources, transfer.peer, transfer.attempts
+            ));
+            remove_from_queue(&transfer.id);
+        }
+        // The job stays in the queue until it succeeds.
+        Ok(Some(_job_id)) => (),
+        Err(error) => {
+            transfer.last_error = error.to_string();
+            requeue(&mut QUEUES.lock().unwrap(), transfer);
+        }
+    }
+}
+
+// Replaces the routing of a study by a transfer per destination, attempted
+// right away. Every peer gets its own transfer, so that a peer being down does
+// not delay the others.
+fn route(mut routing: QueuedTransfer) {
+    routing.attempts += 1;
+    let mut router = Router::new(super::get_local_client());
+    let mut destinations = vec![];
+    for study_id in &routing.resources {
+        match router.get_study_destinations(study_id) {
+            Ok(peers) => destinations.extend(peers.into_iter().map(|peer| (peer, study_id))),
+            Err(error) => {
+                routing.last_error = format!("Failed to route study {}: {}", study_id, error);
+                requeue(&mut QUEUES.lock().unwrap(), routing);
+                return;
+            }
+        }
+    }
+
+    for (peer_identifier, study_id) in destinations {
+        let transfer = match queue::enqueue(&peer_identifier, "studies", vec![study_id.clone()]) {
+            Ok(transfer) => transfer,
+            Err(error) => {
+                plugin::error(&format!(
+                    "Failed to queue the transfer of study {} to {}: {:?}",
+                    study_id, peer_identifier, error
+                ));
+                queue::new_transfer(&peer_identifier, "studies", vec![study_id.clone()])
+            }
+        };
+        plugin::get_threadpool().execute(move || {
+            attempt(&super::get_local_client(), transfer);
+        });
+    }
+    remove_from_queue(&routing.id);
+}
+
+// Queues a transfer that failed during a sync, to be retried like the others
+// (within the transfer window of the destination).
+pub fn retry_later(
+    peer_identifier: &str,
+    resource_type: &str,
+    resources: Vec<String>,
+    error: &str,
+) {
+    let mut transfer = queue::new_transfer(peer_identifier, resource_type, resources);
+    transfer.backlog = true;
+    transfer.attempts = 1;
+    transfer.last_error = error.to_string();
+    requeue(&mut QUEUES.lock().unwrap(), transfer);
+}
+
+// Queues a transfer of the sync held back by the upload cap (see `schedule`),
+// to be attempted at `start_at` (seconds since the UNIX epoch).
+pub fn postpone(peer_identifier: &str, resource_type: &str, resources: Vec<String>, start_at: u64) {
+    plugin::info(&format!(
+        "Transfer of {:?} to {} postponed by {} seconds",
+        resources,
+        peer_identifier,
+        start_at.saturating_sub(now())
+    ));
+    let mut transfer = queue::new_transfer(peer_identifier, resource_type, resources);
+    transfer.backlog = true;
+    transfer.next_attempt_at = start_at;
+    save_to_queue(&transfer);
+    QUEUES.lock().unwrap().pending.push(transfer);
+}
+
+// Moves a transfer that will not be retried (e.g. an abandoned job) straight
+// to the dead-letter list.
+pub fn dead_letter(mut transfer: QueuedTransfer, error: &str) {
+    plugin::error(&format!(
+        "Giving up on the transfer of {:?} to {}: {}",
+        transfer.resources, transfer.peer, error
+    ));
+    transfer.last_error = error.to_string();
+    transfer.dead_letter = true;
+    save_to_queue(&transfer);
+    QUEUES.lock().unwrap().dead_letters.push(transfer);
+}
+
+// The resources being routed or transferred: neither pending (see
+// `get_pending`) nor followed as jobs (see `jobs`), they must not be deleted
+// meanwhile (see `retention`).
+pub fn get_attempted_resources() -> Vec<String> {
+    ATTEMPTED_RESOURCES.lock().unwrap().clone()
+}
+
+// Records the resources of an attempt (a routing, or a synchronous transfer)
+// until it is over, see `get_attempted_resources`.
+pub struct Attempt(Vec<String>);
+
+impl Attempt {
+    pub fn start(resources: &[String]) -> Self {
+        ATTEMPTED_RESOURCES
+            .lock()
+            .unwrap()
+            .extend(resources.iter().cloned());
+        Attempt(resources.to_vec())
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        let mut attempted_resources = ATTEMPTED_RESOURCES.lock().unwrap();
+        for resource in &self.0 {
+            if let Some(index) = attempted_resources.iter().position(|id| id == resource) {
+                attempted_resources.swap_remove(index);
+            }
+        }
+    }
+}
+
+pub fn get_pending() -> Vec<QueuedTransfer> {
+    QUEUES.lock().unwrap().pending.clone()
+}
+
+pub fn get_dead_letters() -> Vec<QueuedTransfer> {
+    QUEUES.lock().unwrap().dead_letters.clone()
+}
+
+// Moves every dead letter back to the retry queue, with a fresh attempt count,
+// and returns how many were moved.
+pub fn replay_dead_letters() -> usize {
+    let mut queues = QUEUES.lock().unwrap();
+    let replayed = replay(&mut queues, now());
+    for transfer in replayed {
+        save_to_queue(transfer);
+    }
+    replayed.len()
+}
+
+// Moves the dead letters to the end of the pending transfers, due at `now`,
+// and returns them.
+fn replay(queues: &mut Queues, now: u64) -> &[QueuedTransfer] {
+    let start = queues.pending.len();
+    let dead_letters: Vec<QueuedTransfer> = queues.dead_letters.drain(..).collect();
+    for mut transfer in dead_letters {
+        transfer.attempts = 0;
+        transfer.next_attempt_at = now;
+        transfer.dead_letter = false;
+        queues.pending.push(transfer);
+    }
+    &queues.pending[start..]
+}
+
+pub fn start_worker() {
+    // The worker runs for the whole lifetime of the plugin, it is not taken
+    // from the plugin's thread pool so as to not starve it.
+    thread::spawn(|| loop {
+        thread::sleep(RETRY_POLL_INTERVAL);
+        retry_due_transfers();
+    });
+}
+
+// Attempts the due transfers of every destination on the plugin's thread pool,
+// one destination after the other, so that a destination that is down (and
+// slow to time out) does not hold back the others.
+fn retry_due_transfers() {
+    let batches = {
+        let mut queues = QUEUES.lock().unwrap();
+        let mut busy_destinations = BUSY_DESTINATIONS.lock().unwrap();
+        take_due_transfers(&mut queues, &mut busy_destinations, now())
+    };
+    for (peer_identifier, transfers) in batches {
+        let busy_destination = BusyDestination(peer_identifier);
+        plugin::get_threadpool().execute(move || {
+            let local_orthanc = super::get_local_client();
+            for transfer in transfers {
+                attempt(&local_orthanc, transfer);
+            }
+            drop(busy_destination);
+        });
+    }
+}
+
+// Takes the due transfers out of the pending ones, grouped by destination, and
+// marks their destinations busy. The due transfers of a destination that is
+// still busy with its previous batch stay pending until the batch is over.
+fn take_due_transfers(
+    queues: &mut Queues,
+    busy_destinations: &mut BTreeSet<String>,
+    now: u64,
+) -> BTreeMap<String, Vec<QueuedTransfer>> {
+    let mut batches: BTreeMap<String, Vec<QueuedTransfer>> = BTreeMap::new();
+    let mut pending = vec![];
+    for transfer in queues.pending.drain(..) {
+        if transfer.next_attempt_at > now || busy_destinations.contains(&transfer.peer) {
+            pending.push(transfer);
+        } else {
+            batches
+                .entry(transfer.peer.clone())
+                .or_default()
+                .push(transfer);
+        }
+    }
+    queues.pending = pending;
+    busy_destinations.extend(batches.keys().cloned());
+    batches
+}
+
+// Marks a destination no longer busy once its batch is over, even if an
+// attempt panicked.
+struct BusyDestination(String);
+
+impl Drop for BusyDestination {
+    fn drop(&mut self) {
+        BUSY_DESTINATIONS.lock().unwrap().remove(&self.0);
+    }
+}
+
+// Schedules the next attempt of a failed transfer, or moves it to the
+// dead-letter list once it used up its attempts.
+fn requeue(queues: &mut Queues, mut transfer: QueuedTransfer) {
+    let config = plugin::get_retry_config();
+    match schedule_retry(&config, &mut transfer, now()) {
+        Some(delay) => plugin::warning(&format!(
+            "Transfer of {:?} to {} failed (attempt {}/{}): {}. Retrying in {} seconds.",
+            transfer.resources,
+            transfer.peer,
+            transfer.attempts,
+            config.max_attempts,
+            transfer.last_error,
+            delay.as_secs()
+        )),
+        None => plugin::error(&format!(
+            "Giving up on the transfer of {:?} to {} after {} attempts: {}",
+            transfer.resources, transfer.peer, transfer.attempts, transfer.last_error
+        )),
+    }
+    save_to_queue(&transfer);
+    if transfer.dead_letter {
+        queues.dead_letters.push(transfer);
+    } else {
+        queues.pending.push(transfer);
+    }
+}
+
+// Sets when a failed transfer is attempted next and returns the delay until
+// then, or marks it as a dead letter (and returns `None`) once it used up its
+// attempts.
+fn schedule_retry(
+    config: &plugin::RetryConfig,
+    transfer: &mut QueuedTransfer,
+    now: u64,
+) -> Option<Duration> {
+    if transfer.attempts >= config.max_attempts {
+        transfer.dead_letter = true;
+        return None;
+    }
+    let delay = backoff_delay(config, transfer.attempts);
+    transfer.next_attempt_at = now + delay.as_secs();
+    Some(delay)
+}
+
+// Failing to update the on-disk queue only matters if Orthanc restarts, the
+// transfer goes on from memory.
+fn save_to_queue(transfer: &QueuedTransfer) {
+    if let Err(error) = queue::save(transfer) {
+        plugin::error(&format!(
+            "Failed to save queued transfer {}: {:?}",
+            transfer.id, error
+        ));
+    }
+}
+
+pub fn remove_from_queue(id: &str) {
+    if let Err(error) = queue::remove(id) {
+        plugin::error(&format!(
+            "Failed to remove queued transfer {}: {:?}",
+            id, error
+        ));
+    }
+}
+
+// InitialDelay * Multiplier ^ (attempts - 1), capped to MaxDelay.
+fn backoff_delay(config: &plugin::RetryConfig, attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+    let delay = config.initial_delay_seconds as f64 * config.multiplier.powi(exponent);
+    Duration::from_secs(delay.min(config.max_delay_seconds as f64) as u64)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config() -> plugin::RetryConfig {
+        plugin::RetryConfig {
+            initial_delay_seconds: 30,
+            multiplier: 2.0,
+            max_delay_seconds: 300,
+            max_attempts: 5,
+        }
+    }
+
+    fn get_transfer(peer: &str, next_attempt_at: u64) -> QueuedTransfer {
+        let mut transfer = queue::new_transfer(peer, "studies", vec!["study".to_string()]);
+        transfer.next_attempt_at = next_attempt_at;
+        transfer
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let config = get_config();
+        assert_eq!(backoff_delay(&config, 0), Duration::from_secs(30));
+        assert_eq!(backoff_delay(&config, 1), Duration::from_secs(30));
+        assert_eq!(backoff_delay(&config, 2), Duration::from_secs(60));
+        assert_eq!(backoff_delay(&config, 3), Duration::from_secs(120));
+        assert_eq!(backoff_delay(&config, 4), Duration::from_secs(240));
+        assert_eq!(backoff_delay(&config, 5), Duration::from_secs(300));
+        assert_eq!(backoff_delay(&config, u32::MAX), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn dead_letters_transfers_out_of_attempts() {
+        let config = get_config();
+        let mut transfer = get_transfer("peer", 0);
+        transfer.attempts = 4;
+        assert_eq!(
+            schedule_retry(&config, &mut transfer, 1000),
+            Some(Duration::from_secs(240))
+        );
+        assert_eq!(transfer.next_attempt_at, 1240);
+        assert!(!transfer.dead_letter);
+
+        transfer.attempts = 5;
+        assert_eq!(schedule_retry(&config, &mut transfer, 2000), None);
+        assert!(transfer.dead_letter);
+    }
+
+    #[test]
+    fn replays_dead_letters() {
+        let mut dead_letter = get_transfer("peer", 500);
+        dead_letter.attempts = 5;
+        dead_letter.dead_letter = true;
+        let mut queues = Queues {
+            pending: vec![get_transfer("other", 2000)],
+            dead_letters: vec![dead_letter],
+        };
+
+        let replayed = replay(&mut queues, 1000);
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].peer, "peer");
+        assert_eq!(replayed[0].attempts, 0);
+        assert_eq!(replayed[0].next_attempt_at, 1000);
+        assert!(!replayed[0].dead_letter);
+        assert!(queues.dead_letters.is_empty());
+        assert_eq!(queues.pending.len(), 2);
+    }
+
+    #[test]
+    fn batches_due_transfers_by_destination() {
+        let mut queues = Queues {
+            pending: vec![
+                get_transfer("a", 100),
+                get_transfer("b", 100),
+                get_transfer("a", 200),
+                get_transfer("c", 2000),
+                get_transfer("busy", 100),
+            ],
+            dead_letters: vec![],
+        };
+        let mut busy_destinations = BTreeSet::from(["busy".to_string()]);
+
+        let batches = take_due_transfers(&mut queues, &mut busy_destinations, 1000);
+        assert_eq!(batches.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(batches["a"].len(), 2);
+        assert_eq!(batches["b"].len(), 1);
+        let pending: Vec<&str> = queues.pending.iter().map(|t| t.peer.as_str()).collect();
+        assert_eq!(pending, vec!["c", "busy"]);
+        assert_eq!(
+            busy_destinations.into_iter().collect::<Vec<_>>(),
+            vec!["a", "b", "busy"]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+// Chooses the peers a study is forwarded to, by evaluating the rules of
+// "VaraProxy" -> "Routing" -> "Rules" (see `plugin::TransferRoutingRule`) on
+// the main DICOM tags of the study, of its patient and of its series (e.g.
+// "Modality", "StationName", "BodyPartExamined", "InstitutionName"). The
+// "CallingAet" tag is the AE title of the modality that sent the study, i.e.
+// the "RemoteAET" metadata of its instances.
+//
+// A tag matches when any of its values does, e.g. a study with a CT and an OT
+// series matches both `{"Modality": "CT"}` and `{"Modality": "OT"}`. Tags
+// missing from the study never match.
+//
+// Studies pulled from a peer (see `pull` and `prefetch`) are not forwarded,
+// unless they changed locally since they were pulled.
+
+use super::http::Result;
+use super::plugin;
+use super::plugin::TagPredicate;
+use super::pull;
+use super::OrthancClient;
+
+use regex::Regex;
+use std::collections::HashMap;
+
+const CALLING_AET: &str = "CallingAet";
+
+// Bounds the memory used to remember the study of instances during a full
+// reconcile. Instances are listed in the order they were received, so those
+// of a study mostly come together.
+const MAX_REMEMBERED_INSTANCES: usize = 100_000;
+
+struct Rule {
+    // `None` for invalid regular expressions, which never match.
+    conditions: Vec<(String, Option<Regex>)>,
+    destinations: Vec<String>,
+}
+
+impl Rule {
+    fn new(rule: plugin::TransferRoutingRule) -> Self {
+        Rule {
+            conditions: rule
+                .conditions
+                .into_iter()
+                .map(|(tag, predicate)| (tag, to_regex(&predicate)))
+                .collect(),
+            destinations: rule.destinations,
+        }
+    }
+
+    fn matches(&self, tags: &HashMap<String, Vec<String>>) -> bool {
+        self.conditions.iter().all(|(tag, regex)| {
+            let values = tags.get(tag).map(Vec::as_slice).unwrap_or_default();
+            regex
+                .as_ref()
+                .is_some_and(|regex| values.iter().any(|value| regex.is_match(value)))
+        })
+    }
+}
+
+// Routes the studies of the local Orthanc. The destinations of every study
+// are remembered, so that a router is meant to be used for one sync (or one
+// change) only.
+pub struct Router {
+    local_orthanc: OrthancClient,
+    rules: Vec<Rule>,
+    default_destinations: Vec<String>,
+    // Whether instances must be routed along with their study even without
+    // rules, to leave pulled studies out.
+    pull_enabled: bool,
+    studies: HashMap<String, Vec<String>>,
+    // The study of every instance of the studies routed so far, so that the
+    // instances of a study take a single lookup.
+    instance_studies: HashMap<String, String>,
+}
+
+impl Router {
+    pub fn new(local_orthanc: OrthancClient) -> Self {
+        let rules = plugin::get_transfer_routing_rules()
+            .into_iter()
+            .map(Rule::new)
+            .collect();
+        Router {
+            local_orthanc,
+            rules,
+            default_destinations: plugin::get_default_destinations(),
+            pull_enabled: plugin::get_pull_config().enable || plugin::get_prefetch_config().enable,
+            studies: HashMap::new(),
+            instance_studies: HashMap::new(),
+        }
+    }
+
+    // The identifiers (in "OrthancPeers") of the peers the study is forwarded
+    // to. Without routing rules, the DICOM tags are not even looked at.
+    pub fn get_study_destinations(&mut self, study_id: &str) -> Result<Vec<String>> {
+        if pull::is_pulled(&self.local_orthanc, study_id)? {
+            return Ok(vec![]);
+        }
+        if self.rules.is_empty() {
+            return Ok(self.default_destinations.clone());
+        }
+        if let Some(destinations) = self.studies.get(study_id) {
+            return Ok(destinations.clone());
+        }
+
+        let tags = self.get_study_tags(study_id)?;
+        let destinations = match self.rules.iter().find(|rule| rule.matches(&tags)) {
+            Some(rule) => rule.destinations.clone(),
+            None => self.default_destinations.clone(),
+        };
+        plugin::info(&format!("Routing study {} to {:?}", study_id, destinations));
+        self.studies
+            .insert(study_id.to_string(), destinations.clone());
+        Ok(destinations)
+    }
+
+    // Whether the resource is forwarded to the peer, resources being routed
+    // along with their study. `resource_type` is the name of the route
+    // (`studies` or `instances`).
+    pub fn routes_to(
+        &mut self,
+        resource_type: &str,
+        id: &str,
+        peer_identifier: &str,
+    ) -> Result<bool> {
+        if self.rules.is_empty() && !self.pull_enabled {
+            return Ok(self
+                .default_destinations
+                .iter()
+                .any(|peer| peer == peer_identifier));
+        }
+        let study_id = match resource_type {
+            "instances" => self.get_instance_study(id)?,
+            _ => id.to_string(),
+        };
+        Ok(self
+            .get_study_destinations(&study_id)?
+            .iter()
+            .any(|peer| peer == peer_identifier))
+    }
+
+    fn get_instance_study(&mut self, instance_id: &str) -> Result<String> {
+        if let Some(study_id) = self.instance_studies.get(instance_id) {
+            return Ok(study_id.clone());
+        }
+        let study_id = self.local_orthanc.get_instance_study(instance_id)?.id;
+        if self.instance_studies.len() >= MAX_REMEMBERED_INSTANCES {
+            self.instance_studies.clear();
+        }
+        for series in self.local_orthanc.get_study_series(&study_id)? {
+            for instance_id in series.instances {
+                self.instance_studies.insert(instance_id, study_id.clone());
+            }
+        }
+        Ok(study_id)
+    }
+
+    fn get_study_tags(&self, study_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        let study = self.local_orthanc.get_study(study_id)?;
+        let series = self.local_orthanc.get_study_series(study_id)?;
+        for (tag, value) in study
+            .main_dicom_tags
+            .into_iter()
+            .chain(study.patient_main_dicom_tags)
+            .chain(
+                series
+                    .iter()
+                    .flat_map(|series| series.main_dicom_tags.clone()),
+            )
+        {
+            tags.entry(tag).or_default().push(value.trim().to_string());
+        }
+
+        // Only looked up when needed, as it takes a request per series.
+        let uses_calling_aet = self
+            .rules
+            .iter()
+            .any(|rule| rule.conditions.iter().any(|(tag, _)| tag == CALLING_AET));
+        if uses_calling_aet {
+            for series in &series {
+                let instance_id = match series.instances.first() {
+                    Some(instance_id) => instance_id,
+                    None => continue,
+                };
+                if let Some(remote_aet) = self
+                    .local_orthanc
+                    .get_instance_metadata(instance_id, "RemoteAET")?
+                {
+                    tags.entry(CALLING_AET.to_string())
+                        .or_default()
+                        .push(remote_aet.trim().to_string());
+                }
+            }
+        }
+        Ok(tags)
+    }
+}
+
+fn to_regex(predicate: &TagPredicate) -> Option<Regex> {
+    let pattern = match predicate {
+        TagPredicate::Regex { regex } => regex.clone(),
+        TagPredicate::Pattern(pattern) => {
+            let pattern: String = pattern
+                .chars()
+                .map(|c| match c {
+                    '*' => String::from(".*"),
+                    '?' => String::from("."),
+                    c => regex::escape(&c.to_string()),
+                })
+                .collect();
+            format!("^{}$", pattern)
+        }
+    };
+    match Regex::new(&pattern) {
+        Ok(regex) => Some(regex),
+        Err(error) => {
+            plugin::error(&format!(
+                "Invalid routing pattern {:?}, it never matches: {}",
+                predicate, error
+            ));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn get_rule(rule: serde_json::Value) -> Rule {
+        Rule::new(serde_json::from_value(rule).unwrap())
+    }
+
+    fn get_tags(tags: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        tags.iter()
+            .map(|(tag, values)| {
+                (
+                    tag.to_string(),
+                    values.iter().map(|value| value.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn is_match(predicate: serde_json::Value, value: &str) -> bool {
+        let predicate: TagPredicate = serde_json::from_value(predicate).unwrap();
+        to_regex(&predicate).unwrap().is_match(value)
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(is_match(json!("CT*"), "CT"));
+        assert!(is_match(json!("CT*"), "CT_SCANNER"));
+        assert!(!is_match(json!("CT*"), "MY_CT"));
+        assert!(is_match(json!("*CT*"), "MY_CT_1"));
+        assert!(is_match(json!("CT?"), "CT1"));
+        assert!(!is_match(json!("CT?"), "CT"));
+        assert!(!is_match(json!("CT?"), "CT12"));
+        assert!(is_match(json!("CT"), "CT"));
+        assert!(!is_match(json!("CT"), "CT1"));
+    }
+
+    #[test]
+    fn escapes_regex_metacharacters_in_patterns() {
+        assert!(is_match(json!("A.B"), "A.B"));
+        assert!(!is_match(json!("A.B"), "AxB"));
+        assert!(is_match(json!("(1+1)[x]"), "(1+1)[x]"));
+        assert!(is_match(json!("^CT$|MR"), "^CT$|MR"));
+        assert!(!is_match(json!("^CT$|MR"), "MR"));
+        assert!(is_match(json!("\\*"), "\\anything"));
+    }
+
+    #[test]
+    fn matches_regular_expressions() {
+        assert!(is_match(json!({"Regex": "^(CT|MR)$"}), "MR"));
+        assert!(!is_match(json!({"Regex": "^(CT|MR)$"}), "US"));
+        // Not anchored unless the expression says so.
+        assert!(is_match(json!({"Regex": "CT"}), "MY_CT_1"));
+    }
+
+    #[test]
+    fn matches_rules_with_several_predicates() {
+        let rule = get_rule(json!({
+            "Match": {"Modality": "CT", "StationName": "ER*"},
+            "Destinations": ["a"]
+        }));
+        assert!(rule.matches(&get_tags(&[
+            ("Modality", &["MR", "CT"]),
+            ("StationName", &["ER1"])
+        ])));
+        assert!(!rule.matches(&get_tags(&[
+            ("Modality", &["CT"]),
+            ("StationName", &["WARD1"])
+        ])));
+        // A missing tag matches no predicate.
+        assert!(!rule.matches(&get_tags(&[("Modality", &["CT"])])));
+    }
+
+    #[test]
+    fn matches_rules_without_predicates() {
+        let rule = get_rule(json!({"Destinations": []}));
+        assert!(rule.matches(&HashMap::new()));
+        assert!(rule.destinations.is_empty());
+    }
+}
@@ -0,0 +1,338 @@
+// Transfer windows (see `plugin::TransferWindow`) and the upload cap
+// ("VaraProxy" -> "Transfers" -> "MaxUploadKiBPerSecond").
+//
+// Outside of its window, a destination is skipped by the periodic sync and
+// the reconciles: its sync cursor does not move, so the changes are picked up
+// by the first sync once the window is open. Queued transfers held back by a
+// window, or by the upload cap, are postponed without using an attempt (see
+// `retries`).
+//
+// The upload to every destination is capped on average: the bytes sent to a
+// destination are reserved on its own schedule, at most "MaxUploadKiBPerSecond"
+// per second, and nothing more is sent until the reserved bytes had the time
+// to go. The sizes are those of the DICOM files (`/instances/{id}`, see
+// `get_batches`). Orthanc uploads the files to its peers and modalities
+// itself, so those transfers are split into batches of at most
+// `BATCH_SECONDS` of upload; STOW-RS bodies are throttled as they are read
+// (see `stow`); Orthanc jobs (see `jobs`) reserve their size when they are
+// submitted. Nothing waits for the cap before a transfer starts: transfers
+// of the sync to a destination whose bytes are still reserved are queued
+// instead (see `retries::postpone`).
+
+use super::plugin;
+use super::OrthancClient;
+use crate::dates;
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+const MINUTES_PER_DAY: u64 = 1440;
+
+// How much upload a batch of instances sent by Orthanc may take.
+pub const BATCH_SECONDS: u64 = 10;
+
+// Until when the upload to every destination is reserved.
+static RESERVED_UNTIL: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+// Instances sent together, see `get_batches`.
+pub struct Batch {
+    pub ids: Vec<String>,
+    // In bytes.
+    pub size: u64,
+}
+
+// Returns when a transfer to the destination may start, in seconds since the
+// UNIX epoch, or `None` if it may start now. `backlog` transfers (those of the
+// sync) are held back by the window of the destination, transfers of stable
+// studies only if the window says so.
+pub fn get_start_time(peer_identifier: &str, backlog: bool) -> Option<u64> {
+    let window_opening = get_window_opening(peer_identifier, backlog);
+    let pacing_start_time = get_pacing_start_time(peer_identifier);
+    match (window_opening, pacing_start_time) {
+        (None, None) => None,
+        (window_opening, pacing_start_time) => Some(
+            window_opening
+                .unwrap_or(0)
+                .max(pacing_start_time.unwrap_or(0)),
+        ),
+    }
+}
+
+// Returns when the window of the destination opens next, in seconds since the
+// UNIX epoch, or `None` if it is open (or the destination has none).
+pub fn get_window_opening(peer_identifier: &str, backlog: bool) -> Option<u64> {
+    let window = plugin::get_destination(peer_identifier).transfer_window?;
+    if !backlog && !window.stable_studies {
+        return None;
+    }
+    let (start, end) = match (parse_time(&window.start), parse_time(&window.end)) {
+        (Some(start), Some(end)) => (start, end),
+        _ => {
+            plugin::error(&format!(
+                "Ignoring invalid transfer window of {}: {:?}",
+                peer_identifier, window
+            ));
+            return None;
+        }
+    };
+
+    let now = dates::now();
+    let minute = dates::local_minute_of_day(now);
+    if is_window_open(start, end, minute) {
+        return None;
+    }
+    Some(now - now % 60 + get_minutes_until(start, minute) * 60)
+}
+
+// Returns when the upload to the destination is no longer reserved, in
+// seconds since the UNIX epoch, or `None` if a transfer may start now.
+pub fn get_pacing_start_time(peer_identifier: &str) -> Option<u64> {
+    plugin::get_max_upload_rate()?;
+    let reserved_until = *RESERVED_UNTIL.lock().unwrap().get(peer_identifier)?;
+    let wait = reserved_until.saturating_duration_since(Instant::now());
+    if wait.is_zero() {
+        return None;
+    }
+    Some(dates::now() + wait.as_secs_f64().ceil() as u64)
+}
+
+// Waits until `size` bytes may be sent to the destination, and reserves their
+// upload. Returns right away without an upload cap.
+pub fn wait_for_upload(peer_identifier: &str, size: u64) {
+    if let Some(rate) = plugin::get_max_upload_rate() {
+        thread::sleep(reserve(peer_identifier, rate, size));
+    }
+}
+
+// Reserves the upload of `size` bytes to the destination at `rate` bytes per
+// second, and returns how long to wait before sending them.
+pub fn reserve(peer_identifier: &str, rate: u64, size: u64) -> Duration {
+    let mut reserved_until = RESERVED_UNTIL.lock().unwrap();
+    let now = Instant::now();
+    let (wait, until) = get_reservation(
+        reserved_until.get(peer_identifier).copied(),
+        now,
+        rate,
+        size,
+    );
+    reserved_until.insert(peer_identifier.to_string(), until);
+    wait
+}
+
+// Splits the `resource_ids` ("studies" or "instances", see `resource_type`)
+// into batches of instances of at most `BATCH_SECONDS` of upload to the
+// destination, each to be waited for (see `wait_for_upload`) before it is
+// sent.
+// Without an upload cap, all the resources go in a single batch, of unknown
+// size.
+pub fn get_batches(
+    local_orthanc: &OrthancClient,
+    resource_type: &str,
+    resource_ids: Vec<String>,
+) -> super::http::Result<Vec<Batch>> {
+    let rate = match plugin::get_max_upload_rate() {
+        Some(rate) => rate,
+        None => {
+            return Ok(vec![Batch {
+                ids: resource_ids,
+                size: 0,
+            }])
+        }
+    };
+    let instance_sizes = get_instance_sizes(local_orthanc, resource_type, resource_ids)?;
+    Ok(split_batches(
+        instance_sizes,
+        rate.saturating_mul(BATCH_SECONDS),
+    ))
+}
+
+// The size of the DICOM file of every instance of the `resource_ids`.
+pub fn get_instance_sizes(
+    local_orthanc: &OrthancClient,
+    resource_type: &str,
+    resource_ids: Vec<String>,
+) -> super::http::Result<Vec<(String, u64)>> {
+    let instance_ids = if resource_type == "studies" {
+        let mut instance_ids = vec![];
+        for study_id in resource_ids {
+            instance_ids.extend(local_orthanc.get_study_instance_ids(&study_id)?);
+        }
+        instance_ids
+    } else {
+        resource_ids
+    };
+    instance_ids
+        .into_iter()
+        .map(|instance_id| {
+            let size = local_orthanc.get_instance_file_size(&instance_id)?;
+            Ok((instance_id, size))
+        })
+        .collect()
+}
+
+// Groups instances, in order, into batches of at most `budget` bytes. An
+// instance larger than the budget gets a batch of its own.
+fn split_batches(instance_sizes: Vec<(String, u64)>, budget: u64) -> Vec<Batch> {
+    let mut batches: Vec<Batch> = vec![];
+    for (instance_id, size) in instance_sizes {
+        match batches.last_mut() {
+            Some(batch) if batch.size + size <= budget => {
+                batch.ids.push(instance_id);
+                batch.size += size;
+            }
+            _ => batches.push(Batch {
+                ids: vec![instance_id],
+                size,
+            }),
+        }
+    }
+    batches
+}
+
+// Returns how long to wait before sending `size` bytes at `rate` bytes per
+// second, given that the upload is reserved until `reserved_until`, and until
+// when it is reserved once they are sent.
+fn get_reservation(
+    reserved_until: Option<Instant>,
+    now: Instant,
+    rate: u64,
+    size: u64,
+) -> (Duration, Instant) {
+    let start = reserved_until.map_or(now, |reserved_until| reserved_until.max(now));
+    let duration = Duration::from_secs_f64(size as f64 / rate.max(1) as f64);
+    (start - now, start + duration)
+}
+
+// Whether a window from `start` to `end` is open at `minute` (all in minutes
+// since midnight). A window starting and ending at the same time is always
+// open, one ending before it starts is over midnight.
+fn is_window_open(start: u64, end: u64, minute: u64) -> bool {
+    if start <= end {
+        start == end || (start <= minute && minute < end)
+    } else {
+        minute >= start || minute < end
+    }
+}
+
+// Minutes from `minute` until `start`, the next day if needed.
+fn get_minutes_until(start: u64, minute: u64) -> u64 {
+    (start + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY
+}
+
+// Minutes since midnight of "HH:MM".
+fn parse_time(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: u64 = hours.trim().parse().ok()?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("00:00"), Some(0));
+        assert_eq!(parse_time("06:30"), Some(390));
+        assert_eq!(parse_time("6:05"), Some(365));
+        assert_eq!(parse_time("23:59"), Some(1439));
+        assert_eq!(parse_time(" 20 : 00 "), Some(1200));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("12:60"), None);
+        assert_eq!(parse_time("1200"), None);
+        assert_eq!(parse_time("12:"), None);
+        assert_eq!(parse_time("-1:00"), None);
+        assert_eq!(parse_time("noon"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn opens_windows_within_a_day() {
+        // 08:00 - 18:00
+        assert!(!is_window_open(480, 1080, 479));
+        assert!(is_window_open(480, 1080, 480));
+        assert!(is_window_open(480, 1080, 1079));
+        assert!(!is_window_open(480, 1080, 1080));
+        assert_eq!(get_minutes_until(480, 1080), 840);
+        assert_eq!(get_minutes_until(480, 300), 180);
+    }
+
+    #[test]
+    fn opens_windows_over_midnight() {
+        // 20:00 - 06:00
+        assert!(!is_window_open(1200, 360, 1199));
+        assert!(is_window_open(1200, 360, 1200));
+        assert!(is_window_open(1200, 360, 1439));
+        assert!(is_window_open(1200, 360, 0));
+        assert!(is_window_open(1200, 360, 359));
+        assert!(!is_window_open(1200, 360, 360));
+        assert!(!is_window_open(1200, 360, 720));
+        // Until 20:00 the same day.
+        assert_eq!(get_minutes_until(1200, 360), 840);
+        assert_eq!(get_minutes_until(1200, 1199), 1);
+    }
+
+    #[test]
+    fn opens_windows_starting_and_ending_together() {
+        assert!(is_window_open(600, 600, 0));
+        assert!(is_window_open(600, 600, 600));
+        assert!(is_window_open(600, 600, 1439));
+    }
+
+    #[test]
+    fn reserves_the_upload() {
+        let now = Instant::now();
+        // 1000 bytes at 100 bytes per second take 10 seconds.
+        let (wait, until) = get_reservation(None, now, 100, 1000);
+        assert_eq!(wait, Duration::ZERO);
+        assert_eq!(until, now + Duration::from_secs(10));
+
+        // The next bytes wait for those.
+        let (wait, until) = get_reservation(Some(until), now, 100, 500);
+        assert_eq!(wait, Duration::from_secs(10));
+        assert_eq!(until, now + Duration::from_secs(15));
+
+        // Past reservations do not let more bytes go at once.
+        let later = now + Duration::from_secs(60);
+        let (wait, until) = get_reservation(Some(until), later, 100, 100);
+        assert_eq!(wait, Duration::ZERO);
+        assert_eq!(until, later + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn splits_batches_by_size() {
+        let instance_sizes = vec![
+            (String::from("a"), 40),
+            (String::from("b"), 50),
+            (String::from("c"), 20),
+            (String::from("d"), 250),
+            (String::from("e"), 10),
+        ];
+        let batches = split_batches(instance_sizes, 100);
+        let batches: Vec<(Vec<String>, u64)> = batches
+            .into_iter()
+            .map(|batch| (batch.ids, batch.size))
+            .collect();
+        assert_eq!(
+            batches,
+            vec![
+                (vec![String::from("a"), String::from("b")], 90),
+                (vec![String::from("c")], 20),
+                (vec![String::from("d")], 250),
+                (vec![String::from("e")], 10),
+            ]
+        );
+        assert!(split_batches(vec![], 100).is_empty());
+    }
+}
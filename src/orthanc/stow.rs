@@ -0,0 +1,315 @@
+// Uploads instances to a DICOMweb STOW-RS service (`POST {Url}/studies`), for
+// destinations of type "STOW-RS" (see `plugin::Destination`). Orthanc has no
+// job for this, the DICOM files are read from the local Orthanc
+// (`/instances/{id}/file`) and posted by the plugin as
+// `multipart/related; type="application/dicom"`, a few instances per request.
+//
+// The response (DICOM JSON, see PS3.18 10.5.3) lists the instances the
+// service failed to store in its FailedSOPSequence, which fails the transfer
+// so that it is retried like any other (see `retries`).
+//
+// Under the upload cap (see `schedule`), requests also hold at most
+// `schedule::BATCH_SECONDS` of upload, and their bodies are read no faster
+// than the cap allows.
+
+use super::http::Error;
+use super::http::Result;
+use super::plugin;
+use super::plugin::Destination;
+use super::schedule;
+use super::OrthancClient;
+
+use reqwest::blocking::Body;
+use reqwest::blocking::RequestBuilder;
+use reqwest::header::ACCEPT;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use serde_json as json;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Number of instances posted per request.
+const STOW_BATCH_SIZE: usize = 10;
+
+// How many bytes of a throttled body are sent at once.
+const THROTTLE_CHUNK_SIZE: usize = 64 * 1024;
+
+const FAILED_SOP_SEQUENCE: &str = "00081198";
+const REFERENCED_SOP_INSTANCE_UID: &str = "00081155";
+const FAILURE_REASON: &str = "00081197";
+
+// Stores the instances of `resource_ids` (`resource_type` is "studies" or
+// "instances") on the destination.
+pub fn store(
+    local_orthanc: &OrthancClient,
+    destination: &Destination,
+    resource_type: &str,
+    resource_ids: Vec<String>,
+) -> Result<()> {
+    let instance_ids = if resource_type == "studies" {
+        let mut instance_ids = vec![];
+        for study_id in resource_ids {
+            instance_ids.extend(local_orthanc.get_study_instance_ids(&study_id)?);
+        }
+        instance_ids
+    } else {
+        resource_ids
+    };
+
+    let upload_rate = plugin::get_max_upload_rate();
+    let batches: Vec<Vec<String>> = match upload_rate {
+        Some(_) => schedule::get_batches(local_orthanc, "instances", instance_ids.clone())?
+            .into_iter()
+            .flat_map(|batch| {
+                batch
+                    .ids
+                    .chunks(STOW_BATCH_SIZE)
+                    .map(<[String]>::to_vec)
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+        None => instance_ids
+            .chunks(STOW_BATCH_SIZE)
+            .map(<[String]>::to_vec)
+            .collect(),
+    };
+
+    let mut failures = vec![];
+    for batch in batches {
+        let files = batch
+            .iter()
+            .map(|instance_id| local_orthanc.get_instance_file(instance_id))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        failures.extend(post_instances(destination, upload_rate, files)?);
+    }
+    if !failures.is_empty() {
+        return Err(Error::StoreFailed(failures));
+    }
+    plugin::info(&format!(
+        "Stored {} instances on {}",
+        instance_ids.len(),
+        destination.name
+    ));
+    Ok(())
+}
+
+// Returns the failures listed by the response, if any. With an `upload_rate`
+// (in bytes per second), the body is throttled.
+fn post_instances(
+    destination: &Destination,
+    upload_rate: Option<u64>,
+    files: Vec<Vec<u8>>,
+) -> Result<Vec<String>> {
+    let boundary = format!(
+        "vara-stow-{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_nanos()
+    );
+    let body = build_multipart_body(&boundary, files);
+    let body = match upload_rate {
+        Some(rate) => {
+            // The earlier uploads to the destination are waited for before
+            // the request starts, so that they do not count towards its
+            // timeout.
+            schedule::wait_for_upload(&destination.name, 0);
+            let size = body.len() as u64;
+            Body::sized(
+                ThrottledBody {
+                    body: Cursor::new(body),
+                    peer_identifier: destination.name.clone(),
+                    rate,
+                },
+                size,
+            )
+        }
+        None => Body::from(body),
+    };
+
+    let request = plugin::get_http_client()
+        .post(format!("{}/studies", destination.url.trim_end_matches('/')))
+        .header(
+            CONTENT_TYPE,
+            format!(
+                "multipart/related; type=\"application/dicom\"; boundary={}",
+                boundary
+            ),
+        )
+        .header(ACCEPT, "application/dicom+json")
+        .body(body);
+    let response = authorize(destination, request).send()?;
+
+    // 202 (Accepted) is returned when some instances failed, 409 (Conflict)
+    // when all of them did.
+    let status = response.status();
+    if status != StatusCode::ACCEPTED && status != StatusCode::CONFLICT {
+        response.error_for_status_ref()?;
+    }
+    let text = response.text()?;
+    if text.trim().is_empty() {
+        if status == StatusCode::CONFLICT {
+            return Err(Error::StoreFailed(vec![String::from(
+                "all instances (no details in the response)",
+            )]));
+        }
+        return Ok(vec![]);
+    }
+    let response: json::Value = json::from_str(&text)
+        .map_err(|error| Error::UnexpectedResponse(format!("{}: {}", error, text)))?;
+    Ok(get_failed_instances(&response))
+}
+
+// A request body read no faster than the upload cap of its destination
+// allows, `THROTTLE_CHUNK_SIZE` bytes at a time (see `schedule::reserve`).
+struct ThrottledBody {
+    body: Cursor<Vec<u8>>,
+    peer_identifier: String,
+    // In bytes per second.
+    rate: u64,
+}
+
+impl Read for ThrottledBody {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = buffer.len().min(THROTTLE_CHUNK_SIZE);
+        let count = self.body.read(&mut buffer[..length])?;
+        if count > 0 {
+            thread::sleep(schedule::reserve(
+                &self.peer_identifier,
+                self.rate,
+                count as u64,
+            ));
+        }
+        Ok(count)
+    }
+}
+
+// A `multipart/related` body with a part of type `application/dicom` per file.
+fn build_multipart_body(boundary: &str, files: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut body = vec![];
+    for file in files {
+        body.extend(
+            format!("--{}\r\nContent-Type: application/dicom\r\n\r\n", boundary).as_bytes(),
+        );
+        body.extend(file);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+// "<SOPInstanceUID> (<FailureReason>)" for every item of the
+// FailedSOPSequence.
+fn get_failed_instances(response: &json::Value) -> Vec<String> {
+    let items = match response[FAILED_SOP_SEQUENCE]["Value"].as_array() {
+        Some(items) => items,
+        None => return vec![],
+    };
+    items
+        .iter()
+        .map(|item| {
+            let uid = item[REFERENCED_SOP_INSTANCE_UID]["Value"][0]
+                .as_str()
+                .unwrap_or("unknown instance");
+            match item[FAILURE_REASON]["Value"][0].as_u64() {
+                Some(reason) => format!("{} (failure reason 0x{:04X})", uid, reason),
+                None => uid.to_string(),
+            }
+        })
+        .collect()
+}
+
+// Applies the credentials, headers and timeout configured for `destination`
+// to a request.
+fn authorize(destination: &Destination, mut request: RequestBuilder) -> RequestBuilder {
+    if let Some(username) = &destination.username {
+        request = request.basic_auth(username, destination.password.as_ref());
+    }
+    if let Some(bearer_token) = &destination.bearer_token {
+        request = request.bearer_auth(bearer_token);
+    }
+    for (name, value) in &destination.headers {
+        request = request.header(name, value);
+    }
+    if let Some(timeout_seconds) = destination.timeout_seconds {
+        request = request.timeout(Duration::from_secs(timeout_seconds));
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_multipart_body() {
+        let body = build_multipart_body("b", vec![b"DICM1".to_vec(), b"DICM2".to_vec()]);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "--b\r\nContent-Type: application/dicom\r\n\r\nDICM1\r\n\
+             --b\r\nContent-Type: application/dicom\r\n\r\nDICM2\r\n\
+             --b--\r\n"
+        );
+        assert_eq!(build_multipart_body("b", vec![]), b"--b--\r\n");
+    }
+
+    #[test]
+    fn lists_failed_instances() {
+        let response = json::json!({
+            "00081190": {"vr": "UR", "Value": ["https://pacs/studies/1.2.3"]},
+            "00081198": {"vr": "SQ", "Value": [
+                {
+                    "00081150": {"vr": "UI", "Value": ["1.2.840.10008.5.1.4.1.1.2"]},
+                    "00081155": {"vr": "UI", "Value": ["1.2.3.4"]},
+                    "00081197": {"vr": "US", "Value": [42752]}
+                },
+                {"00081155": {"vr": "UI", "Value": ["1.2.3.5"]}},
+                {"00081197": {"vr": "US", "Value": [272]}}
+            ]}
+        });
+        assert_eq!(
+            get_failed_instances(&response),
+            vec![
+                "1.2.3.4 (failure reason 0xA700)",
+                "1.2.3.5",
+                "unknown instance (failure reason 0x0110)",
+            ]
+        );
+    }
+
+    #[test]
+    fn throttles_bodies_in_chunks() {
+        let body: Vec<u8> = (0..THROTTLE_CHUNK_SIZE * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        // Fast enough for the reads not to wait.
+        let mut throttled_body = ThrottledBody {
+            body: Cursor::new(body.clone()),
+            peer_identifier: String::from("throttles_bodies_in_chunks"),
+            rate: u64::MAX / 2,
+        };
+        let mut buffer = vec![0; THROTTLE_CHUNK_SIZE * 4];
+        assert_eq!(
+            throttled_body.read(&mut buffer).unwrap(),
+            THROTTLE_CHUNK_SIZE
+        );
+
+        let mut read = buffer[..THROTTLE_CHUNK_SIZE].to_vec();
+        throttled_body.read_to_end(&mut read).unwrap();
+        assert_eq!(read, body);
+    }
+
+    #[test]
+    fn lists_no_failed_instances() {
+        assert!(get_failed_instances(&json::json!({})).is_empty());
+        let response = json::json!({"00081199": {"vr": "SQ", "Value": [
+            {"00081155": {"vr": "UI", "Value": ["1.2.3.4"]}}
+        ]}});
+        assert!(get_failed_instances(&response).is_empty());
+    }
+}
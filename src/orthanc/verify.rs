@@ -0,0 +1,267 @@
+// Verifies the transfers to Orthanc peers once they are done (see
+// "VaraProxy" -> "Transfers" -> "Verify"), by comparing the studies on the
+// peer with the local ones: number of series, number of instances and MD5 of
+// the DICOM file of every instance (`/instances/{id}/attachments/dicom/md5`,
+// both Orthancs need "StoreMD5ForAttachments"). Transferred instances are
+// compared by MD5 only.
+//
+// A mismatch is recorded (`GET /vara/transfers/verification-failures`) and
+// fails the transfer, so that the study is sent again by `retries` (or
+// `jobs`, or the next sync).
+
+use super::http::Error;
+use super::http::Resource;
+use super::http::Result;
+use super::plugin;
+use super::OrthancClient;
+
+use serde::Serialize;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Only the most recent failures are kept.
+const MAX_RECORDED_FAILURES: usize = 100;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct VerificationFailure {
+    #[serde(rename = "Peer")]
+    pub peer: String,
+    // The study, or the instance, that differs.
+    #[serde(rename = "Study")]
+    pub study: String,
+    #[serde(rename = "Mismatches")]
+    pub mismatches: Vec<String>,
+    // Seconds since the UNIX epoch.
+    #[serde(rename = "Time")]
+    pub time: u64,
+}
+
+static VERIFICATION_FAILURES: Mutex<VecDeque<VerificationFailure>> = Mutex::new(VecDeque::new());
+
+// Verifies the `resource_ids` ("studies" or "instances", see
+// `resource_type`) on the peer. Other kinds of destinations are not verified.
+pub fn verify_transfer(
+    local_orthanc: &OrthancClient,
+    peer_identifier: &str,
+    resource_type: &str,
+    resource_ids: &[String],
+) -> Result<()> {
+    if !plugin::get_transfer_verification()
+        || plugin::get_destination(peer_identifier).kind != plugin::DestinationType::Peer
+    {
+        return Ok(());
+    }
+    let peer_orthanc = match super::get_peer_client(peer_identifier) {
+        Some(peer_orthanc) => peer_orthanc,
+        None => return Ok(()),
+    };
+
+    let mut mismatches = vec![];
+    for resource_id in resource_ids {
+        let resource_mismatches = if resource_type == "studies" {
+            compare_study(local_orthanc, &peer_orthanc, resource_id)?
+        } else {
+            compare_instance(local_orthanc, &peer_orthanc, resource_id)?
+                .into_iter()
+                .collect()
+        };
+        if resource_mismatches.is_empty() {
+            continue;
+        }
+        plugin::warning(&format!(
+            "{} differs on {}: {:?}",
+            resource_id, peer_identifier, resource_mismatches
+        ));
+        mismatches.extend(
+            resource_mismatches
+                .iter()
+                .map(|mismatch| format!("{}: {}", resource_id, mismatch)),
+        );
+        record_failure(VerificationFailure {
+            peer: peer_identifier.to_string(),
+            study: resource_id.clone(),
+            mismatches: resource_mismatches,
+            time: now(),
+        });
+    }
+    if !mismatches.is_empty() {
+        return Err(Error::VerificationFailed(mismatches));
+    }
+    Ok(())
+}
+
+pub fn get_verification_failures() -> Vec<VerificationFailure> {
+    VERIFICATION_FAILURES
+        .lock()
+        .unwrap()
+        .iter()
+        .cloned()
+        .collect()
+}
+
+fn compare_study(
+    local_orthanc: &OrthancClient,
+    peer_orthanc: &OrthancClient,
+    study_id: &str,
+) -> Result<Vec<String>> {
+    if !peer_orthanc.has_resource("studies", study_id)? {
+        return Ok(vec![String::from("missing on the peer")]);
+    }
+
+    let local_series = local_orthanc.get_study_series(study_id)?;
+    let peer_series = peer_orthanc.get_study_series(study_id)?;
+    let (mut mismatches, instance_ids) = compare_series(&local_series, &peer_series);
+    for instance_id in instance_ids {
+        let local_md5 = local_orthanc.get_instance_md5(instance_id)?;
+        let peer_md5 = peer_orthanc.get_instance_md5(instance_id)?;
+        mismatches.extend(compare_md5(instance_id, local_md5, peer_md5));
+    }
+    Ok(mismatches)
+}
+
+// Returns the mismatch of the instance on the peer, if any.
+fn compare_instance(
+    local_orthanc: &OrthancClient,
+    peer_orthanc: &OrthancClient,
+    instance_id: &str,
+) -> Result<Option<String>> {
+    if !peer_orthanc.has_resource("instances", instance_id)? {
+        return Ok(Some(String::from("missing on the peer")));
+    }
+    let local_md5 = local_orthanc.get_instance_md5(instance_id)?;
+    let peer_md5 = peer_orthanc.get_instance_md5(instance_id)?;
+    Ok(compare_md5(instance_id, local_md5, peer_md5))
+}
+
+// Compares the series of a study, and returns the mismatches along with the
+// instances on both sides, whose MD5 is left to compare.
+fn compare_series<'a>(
+    local_series: &'a [Resource],
+    peer_series: &[Resource],
+) -> (Vec<String>, Vec<&'a String>) {
+    let mut mismatches = vec![];
+    if local_series.len() != peer_series.len() {
+        mismatches.push(format!(
+            "{} series locally, {} on the peer",
+            local_series.len(),
+            peer_series.len()
+        ));
+    }
+
+    // Orthanc IDs are derived from the DICOM UIDs, so instances have the same
+    // ID on both sides.
+    let local_instances: Vec<&String> = local_series
+        .iter()
+        .flat_map(|series| &series.instances)
+        .collect();
+    let peer_instances: HashSet<&String> = peer_series
+        .iter()
+        .flat_map(|series| &series.instances)
+        .collect();
+    if local_instances.len() != peer_instances.len() {
+        mismatches.push(format!(
+            "{} instances locally, {} on the peer",
+            local_instances.len(),
+            peer_instances.len()
+        ));
+    }
+    let mut instance_ids = vec![];
+    for instance_id in local_instances {
+        if peer_instances.contains(instance_id) {
+            instance_ids.push(instance_id);
+        } else {
+            mismatches.push(format!("instance {} missing on the peer", instance_id));
+        }
+    }
+    (mismatches, instance_ids)
+}
+
+// MD5s that are not stored (see "StoreMD5ForAttachments") are not compared.
+fn compare_md5(
+    instance_id: &str,
+    local_md5: Option<String>,
+    peer_md5: Option<String>,
+) -> Option<String> {
+    match (local_md5, peer_md5) {
+        (Some(local_md5), Some(peer_md5)) if local_md5 != peer_md5 => Some(format!(
+            "instance {} has MD5 {} locally, {} on the peer",
+            instance_id, local_md5, peer_md5
+        )),
+        _ => None,
+    }
+}
+
+fn record_failure(failure: VerificationFailure) {
+    let mut failures = VERIFICATION_FAILURES.lock().unwrap();
+    if failures.len() == MAX_RECORDED_FAILURES {
+        failures.pop_front();
+    }
+    failures.push_back(failure);
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn get_series(instances: &[&str]) -> Resource {
+        serde_json::from_value(json!({"ID": "series", "Instances": instances})).unwrap()
+    }
+
+    #[test]
+    fn finds_no_mismatch_in_identical_studies() {
+        let local_series = vec![get_series(&["a", "b"]), get_series(&["c"])];
+        let peer_series = vec![get_series(&["c"]), get_series(&["b", "a"])];
+        let (mismatches, instance_ids) = compare_series(&local_series, &peer_series);
+        assert!(mismatches.is_empty());
+        assert_eq!(instance_ids, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn finds_missing_series_and_instances() {
+        let local_series = vec![get_series(&["a", "b"]), get_series(&["c"])];
+        let peer_series = vec![get_series(&["a"])];
+        let (mismatches, instance_ids) = compare_series(&local_series, &peer_series);
+        assert_eq!(
+            mismatches,
+            vec![
+                "2 series locally, 1 on the peer",
+                "3 instances locally, 1 on the peer",
+                "instance b missing on the peer",
+                "instance c missing on the peer",
+            ]
+        );
+        assert_eq!(instance_ids, vec!["a"]);
+    }
+
+    #[test]
+    fn finds_extra_instances_on_the_peer() {
+        let local_series = vec![get_series(&["a"])];
+        let peer_series = vec![get_series(&["a", "z"])];
+        let (mismatches, _) = compare_series(&local_series, &peer_series);
+        assert_eq!(mismatches, vec!["1 instances locally, 2 on the peer"]);
+    }
+
+    #[test]
+    fn compares_md5s() {
+        let md5 = |md5: &str| Some(md5.to_string());
+        assert_eq!(compare_md5("a", md5("1"), md5("1")), None);
+        assert_eq!(
+            compare_md5("a", md5("1"), md5("2")),
+            Some(String::from("instance a has MD5 1 locally, 2 on the peer"))
+        );
+        assert_eq!(compare_md5("a", None, md5("2")), None);
+        assert_eq!(compare_md5("a", md5("1"), None), None);
+    }
+}
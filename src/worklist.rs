@@ -0,0 +1,144 @@
+// Worklist items are handled as JSON objects in the format accepted by
+// Orthanc's `CreateDicom` service (`{"0008,0050": "1234", ...}`), whatever
+// source they come from. This module holds the sources other than Orthanc's
+// `find-worklist` route, and helpers shared by all sources.
+
+use crate::orthanc::plugin::WorklistEndpoint;
+use crate::orthanc::pull;
+
+use reqwest::blocking::RequestBuilder;
+use serde_json::Value as JsonValue;
+use std::collections::HashSet;
+use std::time::Duration;
+
+pub mod fhir;
+pub mod folder;
+pub mod hl7;
+pub mod routing;
+pub mod ups;
+
+// Merges the worklist items returned by every endpoint into a single list.
+// Items sharing the same AccessionNumber and ScheduledProcedureStepID are
+// considered duplicates, in which case the one from the endpoint configured
+// first is kept. Items may carry more than their JSON, which `json` returns.
+pub fn merge_worklist_items<T>(worklists: Vec<Vec<T>>, json: fn(&T) -> &JsonValue) -> Vec<T> {
+    let mut seen_keys = HashSet::new();
+    let mut merged_items = vec![];
+    for item in worklists.into_iter().flatten() {
+        if let Some(key) = worklist_item_key(json(&item)) {
+            if !seen_keys.insert(key) {
+                continue;
+            }
+        }
+        merged_items.push(item);
+    }
+    merged_items
+}
+
+// Returns `None` for items that have neither an AccessionNumber nor a
+// ScheduledProcedureStepID, as those cannot be told apart.
+fn worklist_item_key(item: &JsonValue) -> Option<(String, String)> {
+    let accession_number = tag_value(item, "0008,0050", "AccessionNumber").unwrap_or_default();
+    let step_id = [
+        item.get("0040,0100"),
+        item.get("ScheduledProcedureStepSequence"),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(|sequence| sequence.get(0))
+    .find_map(|step| tag_value(step, "0040,0009", "ScheduledProcedureStepID"))
+    .unwrap_or_default();
+
+    if accession_number.is_empty() && step_id.is_empty() {
+        None
+    } else {
+        Some((accession_number.to_string(), step_id.to_string()))
+    }
+}
+
+// Worklist items may use either the hexadecimal tag (`"0008,0050"`) or the
+// tag name (`"AccessionNumber"`) as key, both are accepted by `create_dicom`.
+pub fn tag_value<'a>(item: &'a JsonValue, tag: &str, name: &str) -> Option<&'a str> {
+    item.get(tag)
+        .or_else(|| item.get(name))
+        .and_then(|value| value.as_str())
+}
+
+// The PatientID of the item, unless it is empty or has wildcards (see
+// `pull::is_single_patient_id`): the priors of such items are not
+// pulled, as they would be those of many patients.
+pub fn get_patient_id(item: &JsonValue) -> Option<&str> {
+    tag_value(item, "0010,0020", "PatientID")
+        .filter(|patient_id| pull::is_single_patient_id(patient_id))
+}
+
+// Applies the credentials and timeout configured for `endpoint` to a request.
+pub fn authorize(endpoint: &WorklistEndpoint, mut request: RequestBuilder) -> RequestBuilder {
+    if let Some(username) = &endpoint.username {
+        request = request.basic_auth(username, endpoint.password.as_ref());
+    }
+    if let Some(timeout_seconds) = endpoint.timeout_seconds {
+        request = request.timeout(Duration::from_secs(timeout_seconds));
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merges_duplicates_keeping_the_first_endpoint() {
+        let first = json!({
+            "0008,0050": "A1",
+            "0040,0100": [{"0040,0009": "S1"}],
+            "Source": "first"
+        });
+        let second = json!({
+            "AccessionNumber": "A1",
+            "ScheduledProcedureStepSequence": [{"ScheduledProcedureStepID": "S1"}],
+            "Source": "second"
+        });
+        let other_step = json!({"0008,0050": "A1", "0040,0100": [{"0040,0009": "S2"}]});
+        let merged = merge_worklist_items(
+            vec![vec![first.clone()], vec![second, other_step.clone()]],
+            |item| item,
+        );
+        assert_eq!(merged, vec![first, other_step]);
+    }
+
+    #[test]
+    fn keeps_items_that_cannot_be_told_apart() {
+        let item = json!({"0010,0020": "P1"});
+        let merged =
+            merge_worklist_items(vec![vec![item.clone()], vec![item.clone()]], |item| item);
+        assert_eq!(merged, vec![item.clone(), item]);
+    }
+
+    #[test]
+    fn keys_items_by_accession_number_or_step_id() {
+        assert_eq!(
+            worklist_item_key(&json!({"0008,0050": "A1"})),
+            Some((String::from("A1"), String::new()))
+        );
+        assert_eq!(
+            worklist_item_key(&json!({"0040,0100": [{"0040,0009": "S1"}]})),
+            Some((String::new(), String::from("S1")))
+        );
+        assert_eq!(
+            worklist_item_key(&json!({"0008,0050": "", "0040,0100": []})),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_wildcard_patient_ids() {
+        assert_eq!(get_patient_id(&json!({"0010,0020": "123"})), Some("123"));
+        assert_eq!(get_patient_id(&json!({"PatientID": "123"})), Some("123"));
+        assert_eq!(get_patient_id(&json!({"0010,0020": ""})), None);
+        assert_eq!(get_patient_id(&json!({"0010,0020": "*"})), None);
+        assert_eq!(get_patient_id(&json!({"PatientID": "12?"})), None);
+        assert_eq!(get_patient_id(&json!({"0008,0050": "A1"})), None);
+    }
+}
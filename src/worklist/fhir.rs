@@ -0,0 +1,499 @@
+// Worklist source fetching the active ServiceRequest resources of a FHIR R4
+// server, along with the Patient resources they refer to, and mapping them to
+// worklist items:
+//
+//   ServiceRequest.identifier (type ACSN)          -> AccessionNumber
+//   ServiceRequest.id                              -> RequestedProcedureID, ScheduledProcedureStepID
+//   ServiceRequest.code                            -> RequestedProcedureDescription, RequestedProcedureCodeSequence
+//   ServiceRequest.category/orderDetail (DCM code) -> Modality
+//   ServiceRequest.occurrence[x]                   -> ScheduledProcedureStepStartDate/Time
+//   ServiceRequest.requester                       -> RequestingPhysician
+//   ServiceRequest.locationReference               -> ScheduledProcedureStepLocation
+//   Patient.identifier (type MR), name, birthDate, gender -> Patient module
+//
+// The keys of the C-FIND query are translated into search parameters, so that
+// the server only returns the matching requests.
+
+use crate::orthanc::plugin;
+use crate::orthanc::plugin::WorklistEndpoint;
+
+use reqwest::header::ACCEPT;
+use serde_json::json;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+use std::time::Instant;
+
+const DICOM_CODING_SYSTEM: &str = "http://dicom.nema.org/resources/ontology/DCM";
+
+// Pages of 100 requests fetched at most for a single C-FIND, so that a server
+// paginating without end does not hold the query forever.
+const MAX_PAGES: usize = 50;
+
+pub fn fetch_worklist(
+    endpoint: &WorklistEndpoint,
+    worklist_query: &JsonValue,
+) -> Result<Vec<JsonValue>, Box<dyn Error>> {
+    let http_client = plugin::get_http_client();
+    let mut service_requests = vec![];
+    let mut patients = HashMap::new();
+
+    // Search results are paginated, the URL of the next page (if any) is in
+    // the `next` link of the returned Bundle, along with the search
+    // parameters. The "TimeoutSeconds" of the endpoint applies to all the
+    // pages together.
+    let deadline = endpoint
+        .timeout_seconds
+        .map(|timeout_seconds| Instant::now() + Duration::from_secs(timeout_seconds));
+    let mut next_url = Some(format!(
+        "{}/ServiceRequest",
+        endpoint.url.trim_end_matches('/')
+    ));
+    let mut visited_urls = HashSet::new();
+    let mut parameters = search_parameters(worklist_query);
+    while let Some(url) = next_url.take() {
+        if visited_urls.len() == MAX_PAGES {
+            return Err(
+                format!("More than {} pages of ServiceRequest resources", MAX_PAGES).into(),
+            );
+        }
+        if !visited_urls.insert(url.clone()) {
+            return Err(format!("Pagination loops back to {}", url).into());
+        }
+        let request = http_client
+            .get(&url)
+            .query(&std::mem::take(&mut parameters))
+            .header(ACCEPT, "application/fhir+json");
+        let mut request = super::authorize(endpoint, request);
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(format!("Timed out after {} pages", visited_urls.len() - 1).into());
+            }
+            request = request.timeout(remaining);
+        }
+        let bundle: JsonValue = request.send()?.error_for_status()?.json()?;
+
+        for entry in bundle["entry"].as_array().into_iter().flatten() {
+            let resource = &entry["resource"];
+            match resource["resourceType"].as_str() {
+                Some("ServiceRequest") => service_requests.push(resource.clone()),
+                Some("Patient") => {
+                    let id = resource["id"].as_str().unwrap_or_default();
+                    patients.insert(format!("Patient/{}", id), resource.clone());
+                }
+                _ => (),
+            }
+        }
+
+        next_url = bundle["link"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|link| link["relation"] == "next")
+            .and_then(|link| link["url"].as_str())
+            .map(String::from);
+    }
+
+    Ok(service_requests
+        .iter()
+        .map(|service_request| {
+            let patient = service_request["subject"]["reference"]
+                .as_str()
+                .and_then(|reference| patients.get(&relative_reference(reference)));
+            worklist_item(service_request, patient.unwrap_or(&JsonValue::Null))
+        })
+        .collect())
+}
+
+// Builds the search parameters from the C-FIND query (with human-readable tag
+// names, see `worklist_query_json`). FHIR has no wildcards, keys with
+// wildcards (and keys without a search parameter, e.g. the patient's name or
+// the modality, which can be in the category, the order detail or the code of
+// the request) are only matched by `dicom_matches_query`.
+fn search_parameters(worklist_query: &JsonValue) -> Vec<(String, String)> {
+    let mut parameters = vec![
+        (String::from("status"), String::from("active")),
+        (
+            String::from("_include"),
+            String::from("ServiceRequest:subject"),
+        ),
+        (String::from("_count"), String::from("100")),
+    ];
+    let mut add = |parameter: &str, value: &JsonValue| {
+        if let Some(value) = value.as_str() {
+            if !value.is_empty() && !value.contains(['*', '?']) {
+                // A DICOM list of values is a FHIR "or".
+                parameters.push((parameter.to_string(), value.replace('\\', ",")));
+            }
+        }
+    };
+
+    add("subject:Patient.identifier", &worklist_query["PatientID"]);
+    add("identifier", &worklist_query["AccessionNumber"]);
+    add(
+        "code",
+        &worklist_query["RequestedProcedureCodeSequence"][0]["CodeValue"],
+    );
+
+    let step = &worklist_query["ScheduledProcedureStepSequence"][0];
+    if let Some(date_range) = step["ScheduledProcedureStepStartDate"].as_str() {
+        for (prefix, date) in date_range_bounds(date_range) {
+            parameters.push((String::from("occurrence"), format!("{}{}", prefix, date)));
+        }
+    }
+    parameters
+}
+
+// `20231018` -> `ge2023-10-18`, `le2023-10-18`, `20231018-` -> `ge2023-10-18`,
+// etc.
+fn date_range_bounds(date_range: &str) -> Vec<(&'static str, String)> {
+    let fhir_date = |date: &str| -> Option<String> {
+        if date.len() != 8 || !date.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        Some(format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]))
+    };
+    let (from, to) = date_range
+        .split_once('-')
+        .unwrap_or((date_range, date_range));
+    let mut bounds = vec![];
+    if let Some(from) = fhir_date(from) {
+        bounds.push(("ge", from));
+    }
+    if let Some(to) = fhir_date(to) {
+        bounds.push(("le", to));
+    }
+    bounds
+}
+
+// References may be absolute (`http://server/fhir/Patient/123`) or relative
+// (`Patient/123`).
+fn relative_reference(reference: &str) -> String {
+    let segments: Vec<&str> = reference
+        .trim_end_matches('/')
+        .rsplit('/')
+        .take(2)
+        .collect();
+    segments.into_iter().rev().collect::<Vec<&str>>().join("/")
+}
+
+fn worklist_item(service_request: &JsonValue, patient: &JsonValue) -> JsonValue {
+    let id = service_request["id"].as_str().unwrap_or_default();
+    let accession_number = identifier_value(&service_request["identifier"], "ACSN")
+        .or_else(|| service_request["identifier"][0]["value"].as_str())
+        .unwrap_or(id);
+    let study_instance_uid = service_request["identifier"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|identifier| identifier["system"] == "urn:dicom:uid")
+        .and_then(|identifier| identifier["value"].as_str())
+        .map(|uid| uid.trim_start_matches("urn:oid:"))
+        .unwrap_or_default();
+
+    let code = &service_request["code"];
+    let coding = &code["coding"][0];
+    let description = code["text"]
+        .as_str()
+        .or_else(|| coding["display"].as_str())
+        .unwrap_or_default();
+
+    let modality = service_request["category"]
+        .as_array()
+        .into_iter()
+        .chain(service_request["orderDetail"].as_array())
+        .flatten()
+        .chain(std::iter::once(code))
+        .flat_map(|concept| concept["coding"].as_array().into_iter().flatten())
+        .find(|coding| coding["system"] == DICOM_CODING_SYSTEM)
+        .and_then(|coding| coding["code"].as_str())
+        .unwrap_or_default();
+
+    let occurrence = service_request["occurrenceDateTime"]
+        .as_str()
+        .or_else(|| service_request["occurrencePeriod"]["start"].as_str())
+        .unwrap_or_default();
+
+    let mut item = json!({
+        "0008,0005": "ISO_IR 192",
+        "0008,0050": accession_number,
+        "0010,0010": person_name(&patient["name"]),
+        "0010,0020": identifier_value(&patient["identifier"], "MR")
+            .or_else(|| patient["identifier"][0]["value"].as_str())
+            .unwrap_or_default(),
+        "0010,0030": fhir_date(patient["birthDate"].as_str().unwrap_or_default()),
+        "0010,0040": match patient["gender"].as_str() {
+            Some("male") => "M",
+            Some("female") => "F",
+            Some("other") => "O",
+            _ => "",
+        },
+        "0032,1032": service_request["requester"]["display"].as_str().unwrap_or_default(),
+        "0032,1060": description,
+        "0040,1001": id,
+        "0040,0100": [{
+            "0008,0060": modality,
+            "0040,0002": fhir_date(occurrence),
+            "0040,0003": fhir_time(occurrence),
+            "0040,0007": description,
+            "0040,0009": id,
+            "0040,0011": service_request["locationReference"][0]["display"]
+                .as_str()
+                .unwrap_or_default(),
+        }],
+    });
+
+    if !study_instance_uid.is_empty() {
+        item["0020,000D"] = json!(study_instance_uid);
+    }
+    if let Some(code_value) = coding["code"].as_str() {
+        item["0032,1064"] = json!([{
+            "0008,0100": code_value,
+            "0008,0102": coding_scheme_designator(coding["system"].as_str().unwrap_or_default()),
+            "0008,0104": coding["display"].as_str().unwrap_or(description),
+        }]);
+    }
+    item
+}
+
+// Returns the value of the first identifier whose type is `type_code` (from
+// the HL7 v2-0203 identifier types, e.g. `MR` or `ACSN`).
+fn identifier_value<'a>(identifiers: &'a JsonValue, type_code: &str) -> Option<&'a str> {
+    identifiers
+        .as_array()?
+        .iter()
+        .find(|identifier| {
+            identifier["type"]["coding"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|coding| coding["code"] == type_code)
+        })
+        .and_then(|identifier| identifier["value"].as_str())
+}
+
+// DICOM PN: family^given^middle^prefix^suffix
+fn person_name(names: &JsonValue) -> String {
+    let names = names.as_array().map(Vec::as_slice).unwrap_or_default();
+    let name = names
+        .iter()
+        .find(|name| name["use"] == "official")
+        .or_else(|| names.first());
+    let name = match name {
+        Some(name) => name,
+        None => return String::new(),
+    };
+
+    let strings = |value: &JsonValue| -> Vec<String> {
+        value
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.as_str().map(String::from))
+            .collect()
+    };
+    let given = strings(&name["given"]);
+    let components = [
+        name["family"].as_str().unwrap_or_default().to_string(),
+        given.first().cloned().unwrap_or_default(),
+        given
+            .iter()
+            .skip(1)
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(" "),
+        strings(&name["prefix"]).join(" "),
+        strings(&name["suffix"]).join(" "),
+    ];
+    components.join("^").trim_end_matches('^').to_string()
+}
+
+// FHIR dates and date-times are `YYYY-MM-DDThh:mm:ss+zz:zz`, with every part
+// after the year being optional.
+fn fhir_date(datetime: &str) -> String {
+    datetime.chars().take(10).filter(|c| *c != '-').collect()
+}
+
+fn fhir_time(datetime: &str) -> String {
+    datetime
+        .chars()
+        .skip(11)
+        .take(8)
+        .filter(|c| *c != ':')
+        .collect()
+}
+
+fn coding_scheme_designator(system: &str) -> &str {
+    match system {
+        "http://loinc.org" => "LN",
+        "http://snomed.info/sct" => "SCT",
+        "http://www.radlex.org" => "RADLEX",
+        DICOM_CODING_SYSTEM => "DCM",
+        _ => "99FHIR",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameter_values<'a>(parameters: &'a [(String, String)], name: &str) -> Vec<&'a str> {
+        parameters
+            .iter()
+            .filter(|(parameter, _)| parameter == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn maps_query_keys_to_search_parameters() {
+        let parameters = search_parameters(&json!({
+            "PatientID": "12345",
+            "PatientName": "DOE*",
+            "AccessionNumber": "ACC1\\ACC2",
+            "RequestedProcedureCodeSequence": [{"CodeValue": "24558-9"}],
+            "ScheduledProcedureStepSequence": [{
+                "Modality": "CT",
+                "ScheduledProcedureStepStartDate": "20231018-20231020",
+            }],
+        }));
+        assert_eq!(parameter_values(&parameters, "status"), vec!["active"]);
+        assert_eq!(
+            parameter_values(&parameters, "subject:Patient.identifier"),
+            vec!["12345"]
+        );
+        assert_eq!(
+            parameter_values(&parameters, "identifier"),
+            vec!["ACC1,ACC2"]
+        );
+        assert_eq!(parameter_values(&parameters, "code"), vec!["24558-9"]);
+        assert_eq!(
+            parameter_values(&parameters, "occurrence"),
+            vec!["ge2023-10-18", "le2023-10-20"]
+        );
+        assert_eq!(parameters.len(), 8);
+    }
+
+    #[test]
+    fn skips_empty_and_wildcard_keys() {
+        let parameters = search_parameters(&json!({
+            "PatientID": "",
+            "AccessionNumber": "ACC?",
+            "ScheduledProcedureStepSequence": [{"ScheduledProcedureStepStartDate": ""}],
+        }));
+        assert_eq!(parameters.len(), 3);
+    }
+
+    #[test]
+    fn converts_date_ranges() {
+        assert_eq!(
+            date_range_bounds("20231018"),
+            vec![
+                ("ge", "2023-10-18".to_string()),
+                ("le", "2023-10-18".to_string())
+            ]
+        );
+        assert_eq!(
+            date_range_bounds("20231018-"),
+            vec![("ge", "2023-10-18".to_string())]
+        );
+        assert_eq!(
+            date_range_bounds("-20231018"),
+            vec![("le", "2023-10-18".to_string())]
+        );
+        assert!(date_range_bounds("2023").is_empty());
+    }
+
+    #[test]
+    fn resolves_references() {
+        assert_eq!(relative_reference("Patient/123"), "Patient/123");
+        assert_eq!(
+            relative_reference("http://server/fhir/Patient/123/"),
+            "Patient/123"
+        );
+    }
+
+    #[test]
+    fn maps_service_requests_to_worklist_items() {
+        let service_request = json!({
+            "resourceType": "ServiceRequest",
+            "id": "sr1",
+            "identifier": [
+                {"type": {"coding": [{"code": "PLAC"}]}, "value": "P100"},
+                {"type": {"coding": [{"code": "ACSN"}]}, "value": "ACC1"},
+                {"system": "urn:dicom:uid", "value": "urn:oid:1.2.3"}
+            ],
+            "category": [{"coding": [{"system": DICOM_CODING_SYSTEM, "code": "CT"}]}],
+            "code": {
+                "coding": [{"system": "http://loinc.org", "code": "24725-4", "display": "CT Head"}],
+                "text": "CT head without contrast"
+            },
+            "subject": {"reference": "Patient/p1"},
+            "occurrenceDateTime": "2023-10-18T14:30:00+02:00",
+            "requester": {"display": "Dr House"},
+            "locationReference": [{"display": "Room 1"}]
+        });
+        let patient = json!({
+            "resourceType": "Patient",
+            "id": "p1",
+            "identifier": [
+                {"value": "SSN1"},
+                {"type": {"coding": [{"code": "MR"}]}, "value": "12345"}
+            ],
+            "name": [
+                {"use": "nickname", "given": ["Johnny"]},
+                {"use": "official", "family": "Doe", "given": ["John", "Quincy"],
+                 "prefix": ["Dr"], "suffix": ["Jr"]}
+            ],
+            "birthDate": "1980-01-01",
+            "gender": "male"
+        });
+
+        assert_eq!(
+            worklist_item(&service_request, &patient),
+            json!({
+                "0008,0005": "ISO_IR 192",
+                "0008,0050": "ACC1",
+                "0010,0010": "Doe^John^Quincy^Dr^Jr",
+                "0010,0020": "12345",
+                "0010,0030": "19800101",
+                "0010,0040": "M",
+                "0020,000D": "1.2.3",
+                "0032,1032": "Dr House",
+                "0032,1060": "CT head without contrast",
+                "0032,1064": [{
+                    "0008,0100": "24725-4",
+                    "0008,0102": "LN",
+                    "0008,0104": "CT Head",
+                }],
+                "0040,1001": "sr1",
+                "0040,0100": [{
+                    "0008,0060": "CT",
+                    "0040,0002": "20231018",
+                    "0040,0003": "143000",
+                    "0040,0007": "CT head without contrast",
+                    "0040,0009": "sr1",
+                    "0040,0011": "Room 1",
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn maps_service_requests_without_patient() {
+        let item = worklist_item(
+            &json!({"id": "sr2", "occurrencePeriod": {"start": "2023-10-18"}}),
+            &JsonValue::Null,
+        );
+        assert_eq!(item["0008,0050"], "sr2");
+        assert_eq!(item["0010,0010"], "");
+        assert_eq!(item["0010,0020"], "");
+        assert_eq!(item["0040,0100"][0]["0040,0002"], "20231018");
+        assert_eq!(item["0040,0100"][0]["0040,0003"], "");
+        assert!(item.get("0032,1064").is_none());
+        assert!(item.get("0020,000D").is_none());
+    }
+}
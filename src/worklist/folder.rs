@@ -0,0 +1,65 @@
+// Worklist source reading the files dropped in a local directory, e.g. by a
+// scheduling tool. Two kinds of files are supported:
+//
+// - `*.json`: a worklist item, or an array of worklist items, in the format
+//   accepted by `create_dicom`.
+// - `*.wl`: a DICOM worklist file, as used by Orthanc's sample worklist plugin.
+//
+// Files that cannot be read are skipped with a warning so that a single
+// broken (or partially written) file does not hide the rest of the worklist.
+
+use crate::cache;
+use crate::orthanc::plugin;
+use crate::orthanc::plugin::OrthancPluginMemoryBuffer;
+
+use libc::c_void;
+use serde_json::Value as JsonValue;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+pub fn read_worklist(directory: &Path) -> Result<JsonValue, Box<dyn Error>> {
+    let mut items = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let extension = match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => extension.to_ascii_lowercase(),
+            None => continue,
+        };
+        let file_items = match extension.as_str() {
+            "json" => read_json_file(&path),
+            "wl" => read_dicom_file(&path).map(|item| vec![item]),
+            _ => continue,
+        };
+        match file_items {
+            Ok(file_items) => items.extend(file_items),
+            Err(error) => plugin::warning(&format!(
+                "Skipping worklist file {}: {:?}",
+                path.display(),
+                error
+            )),
+        }
+    }
+    Ok(JsonValue::Array(items))
+}
+
+fn read_json_file(path: &Path) -> Result<Vec<JsonValue>, Box<dyn Error>> {
+    match serde_json::from_str(&cache::read(path)?)? {
+        JsonValue::Array(items) => Ok(items),
+        item @ JsonValue::Object(_) => Ok(vec![item]),
+        _ => Err("Expected a JSON object or an array of JSON objects".into()),
+    }
+}
+
+fn read_dicom_file(path: &Path) -> Result<JsonValue, Box<dyn Error>> {
+    let mut dicom = fs::read(path)?;
+    // The buffer is owned by `dicom`, it must not be freed by Orthanc.
+    let buffer = OrthancPluginMemoryBuffer {
+        data: dicom.as_mut_ptr() as *mut c_void,
+        size: dicom.len() as u32,
+    };
+    crate::dicom_buffer_to_json(
+        &buffer as *const OrthancPluginMemoryBuffer,
+        plugin::OrthancPluginDicomToJsonFormat_OrthancPluginDicomToJsonFormat_Short,
+    )
+}
@@ -0,0 +1,1007 @@
+// HL7 v2 worklist source. Listens for ORM^O01 and OMI^O23 order messages sent
+// over MLLP, maps them to worklist items and stores those items as `.json`
+// files in the "Path" of the endpoint, from where they are read back by the
+// folder source. Storing items as files keeps the worklist across restarts of
+// Orthanc.
+//
+// Every order (ORC segment) of a message is stored in its own files, named
+// after the placer (ORC-2) or filler (ORC-3) order number. New and changed
+// orders replace previously stored files of the same order, canceled orders
+// (ORC-1 = CA or OC) remove them.
+//
+// Messages are decoded with the character set declared in MSH-18, or as
+// ISO 8859-1 (the HL7 v2 default in practice) if it is missing or unknown.
+
+use crate::cache;
+use crate::dates;
+use crate::orthanc::plugin;
+use crate::orthanc::plugin::WorklistEndpointType;
+
+use encoding_rs::Encoding;
+use serde_json::json;
+use serde_json::Map as JsonMap;
+use serde_json::Value as JsonValue;
+use std::fs;
+use std::io;
+use std::io::prelude::BufRead;
+use std::io::prelude::Read;
+use std::io::prelude::Write;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// MLLP framing: <VT> message <FS><CR>
+const MLLP_START_BLOCK: u8 = 0x0b;
+const MLLP_END_BLOCK: u8 = 0x1c;
+const MLLP_CARRIAGE_RETURN: u8 = 0x0d;
+
+// Orders are a few kilobytes at most, larger frames are rejected (and the
+// connection closed) rather than buffered.
+const MAX_MLLP_FRAME_SIZE: u64 = 1024 * 1024;
+
+// Connections idle (or stalled in the middle of a frame) for this long are
+// closed, senders reconnect for their next message.
+const MLLP_READ_TIMEOUT: Duration = Duration::from_secs(300);
+const MLLP_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Connections accepted at the same time by a listener, every connection
+// taking a thread. Further connections are closed right away.
+const MAX_MLLP_CONNECTIONS: usize = 32;
+
+// Counts a connection for as long as it is handled.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Starts one listener thread for every worklist endpoint of type "HL7".
+pub fn start_listeners() {
+    let endpoints = plugin::get_worklist_endpoints().unwrap_or_default();
+    for endpoint in endpoints {
+        if endpoint.kind != WorklistEndpointType::Hl7 {
+            continue;
+        }
+        // "Port" and "Path" are checked by `get_worklist_endpoints`.
+        let port = endpoint.port.unwrap();
+        let directory = endpoint.path.unwrap();
+        let name = endpoint.name;
+        // Listener threads run for the whole lifetime of the plugin, they are
+        // not taken from the plugin's thread pool so as to not starve it.
+        thread::spawn(move || listen(&name, port, &directory));
+    }
+}
+
+fn listen(name: &str, port: u16, directory: &Path) {
+    if let Err(error) = fs::create_dir_all(directory) {
+        plugin::error(&format!(
+            "[HL7 {}] Unable to create worklist directory {}: {:?}",
+            name,
+            directory.display(),
+            error
+        ));
+        return;
+    }
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => listener,
+        Err(error) => {
+            plugin::error(&format!(
+                "[HL7 {}] Unable to listen on port {}: {:?}",
+                name, port, error
+            ));
+            return;
+        }
+    };
+    plugin::info(&format!("[HL7 {}] Listening on port {}.", name, port));
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_MLLP_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    plugin::warning(&format!(
+                        "[HL7 {}] Closing connection from {:?}: already {} connections open",
+                        name,
+                        stream.peer_addr().ok(),
+                        MAX_MLLP_CONNECTIONS
+                    ));
+                    continue;
+                }
+                let slot = ConnectionSlot(connections.clone());
+                let name = name.to_string();
+                let directory = directory.to_path_buf();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(error) = handle_connection(&name, stream, &directory) {
+                        plugin::warning(&format!("[HL7 {}] Connection failed: {:?}", name, error));
+                    }
+                });
+            }
+            Err(error) => {
+                plugin::warning(&format!(
+                    "[HL7 {}] Failed to accept connection: {:?}",
+                    name, error
+                ));
+            }
+        }
+    }
+}
+
+fn handle_connection(name: &str, mut stream: TcpStream, directory: &Path) -> io::Result<()> {
+    stream.set_read_timeout(Some(MLLP_READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(MLLP_WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    while let Some(frame) = read_mllp_frame(&mut reader)? {
+        let text = decode_message(&frame);
+        let ack = handle_message(name, &text, directory);
+        stream.write_all(&[MLLP_START_BLOCK])?;
+        stream.write_all(ack.as_bytes())?;
+        stream.write_all(&[MLLP_END_BLOCK, MLLP_CARRIAGE_RETURN])?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+// Returns `None` once the peer has closed the connection, or left it idle
+// for `MLLP_READ_TIMEOUT`.
+fn read_mllp_frame(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    // Anything before the start block (e.g. the trailing <CR> of the previous
+    // frame) is discarded.
+    let mut discarded = vec![];
+    match reader
+        .by_ref()
+        .take(MAX_MLLP_FRAME_SIZE)
+        .read_until(MLLP_START_BLOCK, &mut discarded)
+    {
+        Ok(_) => (),
+        Err(error)
+            if discarded.is_empty()
+                && matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+        {
+            return Ok(None)
+        }
+        Err(error) => return Err(error),
+    }
+    if discarded.last() != Some(&MLLP_START_BLOCK) {
+        if discarded.len() as u64 == MAX_MLLP_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No MLLP start block",
+            ));
+        }
+        return Ok(None);
+    }
+
+    // One more byte than the largest frame for the end block.
+    let mut frame = vec![];
+    reader
+        .by_ref()
+        .take(MAX_MLLP_FRAME_SIZE + 1)
+        .read_until(MLLP_END_BLOCK, &mut frame)?;
+    if frame.pop() != Some(MLLP_END_BLOCK) {
+        if frame.len() as u64 >= MAX_MLLP_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("MLLP frame larger than {} bytes", MAX_MLLP_FRAME_SIZE),
+            ));
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed in the middle of an MLLP frame",
+        ));
+    }
+    Ok(Some(frame))
+}
+
+fn decode_message(frame: &[u8]) -> String {
+    match get_declared_charset(frame).and_then(|charset| get_encoding(&charset)) {
+        Some(encoding) => encoding.decode_without_bom_handling(frame).0.into_owned(),
+        // ISO 8859-1 maps every byte to the code point of the same value.
+        None => frame.iter().map(|byte| *byte as char).collect(),
+    }
+}
+
+// The first character set of MSH-18, read from the raw message as the
+// separators and the character set names are ASCII.
+fn get_declared_charset(frame: &[u8]) -> Option<String> {
+    let start = frame.iter().position(|byte| !byte.is_ascii_whitespace())?;
+    let header = frame[start..]
+        .split(|byte| *byte == b'\r' || *byte == b'\n')
+        .next()?;
+    if !header.starts_with(b"MSH") || header.len() < 4 {
+        return None;
+    }
+    let field = header[3];
+    let repetition = header[4..]
+        .iter()
+        .take_while(|byte| **byte != field)
+        .nth(1)
+        .copied()
+        .unwrap_or(b'~');
+    // MSH-1 is the field separator itself, so that MSH-18 is the 17th field
+    // after the segment name.
+    let charsets = header.split(|byte| *byte == field).nth(17)?;
+    let charset = charsets.split(|byte| *byte == repetition).next()?;
+    let charset = String::from_utf8_lossy(charset).trim().to_string();
+    (!charset.is_empty()).then_some(charset)
+}
+
+// The encoding of an HL7 character set (table 0211), `None` for ASCII and
+// unsupported ones.
+fn get_encoding(charset: &str) -> Option<&'static Encoding> {
+    match charset {
+        "UNICODE UTF-8" => Some(encoding_rs::UTF_8),
+        "GB 18030-2000" => Some(encoding_rs::GB18030),
+        "BIG-5" => Some(encoding_rs::BIG5),
+        "KS X 1001" => Some(encoding_rs::EUC_KR),
+        "ISO IR87" => Some(encoding_rs::ISO_2022_JP),
+        _ => {
+            let part = charset.strip_prefix("8859/")?;
+            Encoding::for_label(format!("iso-8859-{}", part).as_bytes())
+        }
+    }
+}
+
+// Applies the message to the worklist and returns the HL7 ACK to send back.
+fn handle_message(name: &str, text: &str, directory: &Path) -> String {
+    let message = match Message::parse(text) {
+        Ok(message) => message,
+        Err(error) => {
+            plugin::warning(&format!("[HL7 {}] Rejecting message: {}", name, error));
+            return ack(None, "AR", &error);
+        }
+    };
+
+    let message_type = format!(
+        "{}^{}",
+        message.msh().component(9, 1),
+        message.msh().component(9, 2)
+    );
+    if message_type != "ORM^O01" && message_type != "OMI^O23" {
+        plugin::warning(&format!(
+            "[HL7 {}] Rejecting unsupported message type {}",
+            name, message_type
+        ));
+        return ack(
+            Some(&message),
+            "AR",
+            &format!("Unsupported message type {}", message_type),
+        );
+    }
+
+    match apply_orders(&message, directory) {
+        Ok(()) => {
+            plugin::info(&format!(
+                "[HL7 {}] Processed {} message {}",
+                name,
+                message_type,
+                message.msh().field(10)
+            ));
+            ack(Some(&message), "AA", "")
+        }
+        Err(error) => {
+            plugin::error(&format!(
+                "[HL7 {}] Failed to process {} message {}: {:?}",
+                name,
+                message_type,
+                message.msh().field(10),
+                error
+            ));
+            ack(Some(&message), "AE", &error.to_string())
+        }
+    }
+}
+
+fn apply_orders(message: &Message, directory: &Path) -> io::Result<()> {
+    for order in message.orders() {
+        let order_key = order.key();
+        if order_key.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Order without placer or filler order number",
+            ));
+        }
+
+        let order_control = order.orc.field(1);
+        let items = if order_control == "CA" || order_control == "OC" {
+            vec![]
+        } else {
+            worklist_items(message, &order)
+        };
+        for (index, item) in items.iter().enumerate() {
+            let path = directory.join(format!("{}{}.json", order_file_prefix(&order_key), index));
+            cache::write(&item.to_string(), &path)?;
+        }
+        // Items written for a previous version of the order may outnumber the
+        // current ones.
+        remove_order_files(directory, &order_key, items.len())?;
+    }
+    Ok(())
+}
+
+fn order_file_prefix(order_key: &str) -> String {
+    format!("hl7_{}_", cache::sanitize_key(order_key))
+}
+
+// Removes the files of the order whose index is `first_index` or above.
+fn remove_order_files(directory: &Path, order_key: &str, first_index: usize) -> io::Result<()> {
+    let prefix = order_file_prefix(order_key);
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let file_name = path.file_name().and_then(|f| f.to_str()).unwrap_or("");
+        // Only `<prefix><index>.json` belongs to this order, `<prefix>...`
+        // could be the files of another order whose key starts with this one.
+        let index = file_name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".json"))
+            .filter(|index| !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|index| index.parse::<usize>().ok());
+        match index {
+            Some(index) if index >= first_index => fs::remove_file(&path)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Maps an order to worklist items, one per Imaging Procedure Control (IPC)
+// segment, or a single one for orders without IPC segments (e.g. ORM^O01).
+fn worklist_items(message: &Message, order: &Order) -> Vec<JsonValue> {
+    let empty = Segment::default();
+    let pid = message.segment("PID").unwrap_or(&empty);
+    let pv1 = message.segment("PV1").unwrap_or(&empty);
+    let obr = order.obr.unwrap_or(&empty);
+
+    let mut ipcs: Vec<&Segment> = order.ipcs.clone();
+    if ipcs.is_empty() {
+        ipcs.push(&empty);
+    }
+
+    let accession_number = first_non_empty(&[
+        ipcs[0].component(1, 1),
+        obr.component(18, 1),
+        order.orc.component(3, 1),
+        order.orc.component(2, 1),
+    ]);
+    let start_datetime = first_non_empty(&[
+        order.tq1.map(|tq1| tq1.component(7, 1)).unwrap_or_default(),
+        obr.component(27, 4),
+        obr.component(7, 1),
+    ]);
+    let procedure_code = obr.components(4);
+    let procedure_code = |n: usize| procedure_code.get(n).cloned().unwrap_or_default();
+
+    let mut items = vec![];
+    for ipc in ipcs {
+        let requested_procedure_id = first_non_empty(&[
+            ipc.component(2, 1),
+            obr.component(19, 1),
+            accession_number.clone(),
+        ]);
+        let step_id = first_non_empty(&[
+            ipc.component(4, 1),
+            obr.component(20, 1),
+            requested_procedure_id.clone(),
+        ]);
+
+        let step = json!({
+            "0008,0060": first_non_empty(&[ipc.component(5, 1), obr.component(24, 1)]),
+            "0040,0001": ipc.component(9, 1),
+            "0040,0002": hl7_date(&start_datetime),
+            "0040,0003": hl7_time(&start_datetime),
+            "0040,0007": procedure_code(1),
+            "0040,0009": step_id,
+            "0040,0010": ipc.component(7, 1),
+            "0040,0011": ipc.component(8, 1),
+        });
+
+        let mut item = JsonMap::new();
+        item.insert("0008,0005".into(), json!("ISO_IR 192"));
+        item.insert("0008,0050".into(), json!(accession_number));
+        item.insert(
+            "0008,0090".into(),
+            json!(xcn_to_person_name(&pv1.components(8))),
+        );
+        item.insert(
+            "0010,0010".into(),
+            json!(xpn_to_person_name(&pid.components(5))),
+        );
+        item.insert("0010,0020".into(), json!(pid.component(3, 1)));
+        item.insert("0010,0030".into(), json!(hl7_date(&pid.component(7, 1))));
+        item.insert("0010,0040".into(), json!(pid.component(8, 1)));
+        item.insert(
+            "0032,1032".into(),
+            json!(xcn_to_person_name(&obr.components(16))),
+        );
+        item.insert("0032,1060".into(), json!(procedure_code(1)));
+        item.insert("0038,0010".into(), json!(pv1.component(19, 1)));
+        item.insert("0038,0300".into(), json!(pv1.component(3, 1)));
+        item.insert("0040,1001".into(), json!(requested_procedure_id));
+        item.insert("0040,0100".into(), json!([step]));
+        if !ipc.component(3, 1).is_empty() {
+            item.insert("0020,000D".into(), json!(ipc.component(3, 1)));
+        }
+        if !procedure_code(0).is_empty() {
+            item.insert(
+                "0032,1064".into(),
+                json!([{
+                    "0008,0100": procedure_code(0),
+                    "0008,0102": procedure_code(2),
+                    "0008,0104": procedure_code(1),
+                }]),
+            );
+        }
+        items.push(JsonValue::Object(item));
+    }
+    items
+}
+
+fn first_non_empty(values: &[String]) -> String {
+    values
+        .iter()
+        .find(|value| !value.is_empty())
+        .cloned()
+        .unwrap_or_default()
+}
+
+// HL7 DTM values are `YYYYMMDDHHMMSS.SSSS+ZZZZ` with every part after the year
+// being optional.
+fn hl7_date(datetime: &str) -> String {
+    datetime.chars().take(8).collect()
+}
+
+fn hl7_time(datetime: &str) -> String {
+    datetime
+        .chars()
+        .skip(8)
+        .take_while(|c| c.is_ascii_digit())
+        .take(6)
+        .collect()
+}
+
+// XPN: family^given^middle^suffix^prefix, DICOM PN: family^given^middle^prefix^suffix
+fn xpn_to_person_name(xpn: &[String]) -> String {
+    let component = |n: usize| xpn.get(n).map(String::as_str).unwrap_or("");
+    person_name(&[
+        component(0),
+        component(1),
+        component(2),
+        component(4),
+        component(3),
+    ])
+}
+
+// XCN: id^family^given^middle^suffix^prefix
+fn xcn_to_person_name(xcn: &[String]) -> String {
+    let component = |n: usize| xcn.get(n).map(String::as_str).unwrap_or("");
+    person_name(&[
+        component(1),
+        component(2),
+        component(3),
+        component(5),
+        component(4),
+    ])
+}
+
+fn person_name(components: &[&str]) -> String {
+    components.join("^").trim_end_matches('^').to_string()
+}
+
+fn ack(message: Option<&Message>, code: &str, text: &str) -> String {
+    let empty = Segment::default();
+    let msh = message.map(|message| message.msh()).unwrap_or(&empty);
+    let processing_id = first_non_empty(&[msh.field(11).to_string(), "P".to_string()]);
+    let version = first_non_empty(&[msh.field(12).to_string(), "2.5".to_string()]);
+    let timestamp = hl7_timestamp();
+    format!(
+        "MSH|^~\\&|{}|{}|{}|{}|{}||ACK^{}^ACK|{}|{}|{}\rMSA|{}|{}|{}\r",
+        msh.field(5),
+        msh.field(6),
+        msh.field(3),
+        msh.field(4),
+        timestamp,
+        msh.component(9, 2),
+        timestamp,
+        processing_id,
+        version,
+        code,
+        msh.field(10),
+        escape(text, &Separators::default()),
+    )
+}
+
+// Current UTC time as an HL7 DTM (`YYYYMMDDHHMMSS`).
+fn hl7_timestamp() -> String {
+    dates::timestamp(dates::now())
+}
+
+// Parsing
+// ----------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+struct Separators {
+    field: char,
+    component: char,
+    repetition: char,
+    escape: char,
+    subcomponent: char,
+}
+
+impl Default for Separators {
+    fn default() -> Self {
+        Separators {
+            field: '|',
+            component: '^',
+            repetition: '~',
+            escape: '\\',
+            subcomponent: '&',
+        }
+    }
+}
+
+#[derive(Default)]
+struct Segment {
+    // `fields[n]` is the raw value of field `n` of the segment, `fields[0]` is
+    // the segment ID.
+    fields: Vec<String>,
+    separators: Separators,
+}
+
+impl Segment {
+    fn id(&self) -> &str {
+        self.field(0)
+    }
+
+    fn field(&self, n: usize) -> &str {
+        self.fields.get(n).map(String::as_str).unwrap_or("")
+    }
+
+    // Unescaped components of the first repetition of field `n`.
+    fn components(&self, n: usize) -> Vec<String> {
+        let first_repetition = self
+            .field(n)
+            .split(self.separators.repetition)
+            .next()
+            .unwrap_or("");
+        first_repetition
+            .split(self.separators.component)
+            .map(|component| unescape(component, &self.separators))
+            .collect()
+    }
+
+    // Component `m` (1-based) of the first repetition of field `n`.
+    fn component(&self, n: usize, m: usize) -> String {
+        self.components(n)
+            .into_iter()
+            .nth(m - 1)
+            .unwrap_or_default()
+    }
+}
+
+struct Message {
+    segments: Vec<Segment>,
+}
+
+struct Order<'a> {
+    orc: &'a Segment,
+    obr: Option<&'a Segment>,
+    tq1: Option<&'a Segment>,
+    ipcs: Vec<&'a Segment>,
+}
+
+impl Order<'_> {
+    fn key(&self) -> String {
+        first_non_empty(&[
+            self.orc.component(2, 1),
+            self.orc.component(3, 1),
+            self.obr.map(|obr| obr.component(2, 1)).unwrap_or_default(),
+            self.obr.map(|obr| obr.component(3, 1)).unwrap_or_default(),
+        ])
+    }
+}
+
+impl Message {
+    fn parse(text: &str) -> Result<Message, String> {
+        let text = text.trim_start_matches(|c: char| c.is_whitespace());
+        if !text.starts_with("MSH") || text.len() < 8 {
+            return Err(String::from("Message does not start with an MSH segment"));
+        }
+
+        let field = text.chars().nth(3).unwrap();
+        let encoding_characters: Vec<char> =
+            text.chars().skip(4).take_while(|c| *c != field).collect();
+        if encoding_characters.is_empty() {
+            return Err(String::from("MSH segment without encoding characters"));
+        }
+        let default = Separators::default();
+        let encoding_character =
+            |n: usize, default: char| encoding_characters.get(n).copied().unwrap_or(default);
+        let separators = Separators {
+            field,
+            component: encoding_character(0, default.component),
+            repetition: encoding_character(1, default.repetition),
+            escape: encoding_character(2, default.escape),
+            subcomponent: encoding_character(3, default.subcomponent),
+        };
+
+        let segments = text
+            .split(['\r', '\n'])
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| {
+                let mut fields: Vec<String> = segment.split(field).map(String::from).collect();
+                // MSH-1 is the field separator itself, so that `fields[n]` is
+                // MSH-n like for every other segment.
+                if fields[0] == "MSH" {
+                    fields.insert(1, field.to_string());
+                }
+                Segment { fields, separators }
+            })
+            .collect();
+        Ok(Message { segments })
+    }
+
+    fn msh(&self) -> &Segment {
+        &self.segments[0]
+    }
+
+    fn segment(&self, id: &str) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.id() == id)
+    }
+
+    // Groups the order detail segments with the ORC segment they follow.
+    fn orders(&self) -> Vec<Order<'_>> {
+        let mut orders: Vec<Order> = vec![];
+        for segment in &self.segments {
+            match (segment.id(), orders.last_mut()) {
+                ("ORC", _) => orders.push(Order {
+                    orc: segment,
+                    obr: None,
+                    tq1: None,
+                    ipcs: vec![],
+                }),
+                ("OBR", Some(order)) => order.obr = Some(segment),
+                ("TQ1", Some(order)) => order.tq1 = order.tq1.or(Some(segment)),
+                ("IPC", Some(order)) => order.ipcs.push(segment),
+                _ => (),
+            }
+        }
+        orders
+    }
+}
+
+fn unescape(value: &str, separators: &Separators) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut parts = value.split(separators.escape);
+    unescaped.push_str(parts.next().unwrap_or(""));
+    // Escape sequences are the odd parts of `\`-separated values.
+    while let Some(sequence) = parts.next() {
+        match sequence {
+            "F" => unescaped.push(separators.field),
+            "S" => unescaped.push(separators.component),
+            "T" => unescaped.push(separators.subcomponent),
+            "R" => unescaped.push(separators.repetition),
+            "E" => unescaped.push(separators.escape),
+            ".br" => unescaped.push_str("\r\n"),
+            // Unsupported escape sequences (e.g. highlighting) are dropped.
+            _ => (),
+        }
+        unescaped.push_str(parts.next().unwrap_or(""));
+    }
+    unescaped
+}
+
+fn escape(value: &str, separators: &Separators) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        let sequence = match c {
+            c if c == separators.escape => "E",
+            c if c == separators.field => "F",
+            c if c == separators.component => "S",
+            c if c == separators.subcomponent => "T",
+            c if c == separators.repetition => "R",
+            _ => {
+                escaped.push(c);
+                continue;
+            }
+        };
+        escaped.push(separators.escape);
+        escaped.push_str(sequence);
+        escaped.push(separators.escape);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    const ORM: &str = "MSH|^~\\&|RIS|HOSP|VARA|BUFFER|20231018120000||ORM^O01|MSG0001|P|2.3\r\
+        PID|1||12345^^^HOSP||DOE^JOHN^Q^JR^DR||19800101|M\r\
+        PV1|1|O|RAD^101|||||9876^SMITH^ANNA|||||||||||V100\r\
+        ORC|NW|P100|F200\r\
+        OBR|1|P100|F200|CTHEAD^CT Head^LOCAL|||20231018143000|||||||||555^HOUSE^GREGORY||ACC1|RP1|SPS1||||CT\r";
+
+    const OMI: &str = "MSH|^~\\&|RIS|HOSP|VARA|BUFFER|20231018120000||OMI^O23|MSG0002|P|2.5\r\
+        PID|1||12345||DOE^JOHN\r\
+        ORC|NW|P300\r\
+        TQ1|||||||20231019080000\r\
+        OBR|1|P300||MRKNEE^MR Knee^LOCAL\r\
+        IPC|ACC3|RP3|1.2.3|SPS3|MR||MR1|ROOM1|MRAET\r\
+        IPC|ACC3|RP3|1.2.3|SPS4|MR||MR2|ROOM2|MRAET2\r";
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "vara_orthanc_hl7_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn list_files(directory: &Path) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn parses_messages() {
+        let message = Message::parse(ORM).unwrap();
+        assert_eq!(message.segments.len(), 5);
+        assert_eq!(message.msh().field(1), "|");
+        assert_eq!(message.msh().field(2), "^~\\&");
+        assert_eq!(message.msh().component(9, 2), "O01");
+        assert_eq!(message.msh().field(10), "MSG0001");
+        assert_eq!(message.segment("PID").unwrap().component(3, 1), "12345");
+        assert_eq!(message.segment("PID").unwrap().component(3, 4), "HOSP");
+        assert!(message.segment("IPC").is_none());
+
+        let orders = message.orders();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].key(), "P100");
+        assert!(orders[0].obr.is_some());
+        assert_eq!(Message::parse(OMI).unwrap().orders()[0].ipcs.len(), 2);
+    }
+
+    #[test]
+    fn parses_custom_separators() {
+        let message = Message::parse("MSH#*$@%#RIS\nPID#1##ID1*X$ID2##DOE*JOHN@F@\n").unwrap();
+        let pid = message.segment("PID").unwrap();
+        assert_eq!(pid.component(3, 1), "ID1");
+        assert_eq!(pid.component(3, 2), "X");
+        // Only the first repetition is read.
+        assert_eq!(pid.components(3).len(), 2);
+        assert_eq!(pid.component(5, 2), "JOHN#");
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        assert!(Message::parse("PID|1||12345").is_err());
+        assert!(Message::parse("MSH").is_err());
+        assert!(Message::parse("MSH||RIS|HOSP").is_err());
+    }
+
+    #[test]
+    fn escapes_and_unescapes() {
+        let separators = Separators::default();
+        let value = "a|b^c&d~e\\f";
+        let escaped = escape(value, &separators);
+        assert_eq!(escaped, "a\\F\\b\\S\\c\\T\\d\\R\\e\\E\\f");
+        assert_eq!(unescape(&escaped, &separators), value);
+        assert_eq!(unescape("line\\.br\\next", &separators), "line\r\nnext");
+        assert_eq!(unescape("\\H\\bold\\N\\", &separators), "bold");
+    }
+
+    #[test]
+    fn maps_orders_to_worklist_items() {
+        let message = Message::parse(ORM).unwrap();
+        let items = worklist_items(&message, &message.orders()[0]);
+        assert_eq!(
+            items,
+            vec![json!({
+                "0008,0005": "ISO_IR 192",
+                "0008,0050": "ACC1",
+                "0008,0090": "SMITH^ANNA",
+                "0010,0010": "DOE^JOHN^Q^DR^JR",
+                "0010,0020": "12345",
+                "0010,0030": "19800101",
+                "0010,0040": "M",
+                "0032,1032": "HOUSE^GREGORY",
+                "0032,1060": "CT Head",
+                "0032,1064": [{
+                    "0008,0100": "CTHEAD",
+                    "0008,0102": "LOCAL",
+                    "0008,0104": "CT Head",
+                }],
+                "0038,0010": "V100",
+                "0038,0300": "RAD",
+                "0040,1001": "RP1",
+                "0040,0100": [{
+                    "0008,0060": "CT",
+                    "0040,0001": "",
+                    "0040,0002": "20231018",
+                    "0040,0003": "143000",
+                    "0040,0007": "CT Head",
+                    "0040,0009": "SPS1",
+                    "0040,0010": "",
+                    "0040,0011": "",
+                }],
+            })]
+        );
+    }
+
+    #[test]
+    fn maps_imaging_procedure_control_segments() {
+        let message = Message::parse(OMI).unwrap();
+        let items = worklist_items(&message, &message.orders()[0]);
+        assert_eq!(items.len(), 2);
+        for item in &items {
+            assert_eq!(item["0008,0050"], "ACC3");
+            assert_eq!(item["0020,000D"], "1.2.3");
+            assert_eq!(item["0040,1001"], "RP3");
+            assert_eq!(item["0040,0100"][0]["0008,0060"], "MR");
+            assert_eq!(item["0040,0100"][0]["0040,0002"], "20231019");
+            assert_eq!(item["0040,0100"][0]["0040,0003"], "080000");
+        }
+        assert_eq!(items[0]["0040,0100"][0]["0040,0009"], "SPS3");
+        assert_eq!(items[0]["0040,0100"][0]["0040,0001"], "MRAET");
+        assert_eq!(items[0]["0040,0100"][0]["0040,0010"], "MR1");
+        assert_eq!(items[0]["0040,0100"][0]["0040,0011"], "ROOM1");
+        assert_eq!(items[1]["0040,0100"][0]["0040,0009"], "SPS4");
+        assert_eq!(items[1]["0040,0100"][0]["0040,0001"], "MRAET2");
+    }
+
+    #[test]
+    fn stores_and_cancels_orders() {
+        let directory = test_directory("orders");
+        apply_orders(&Message::parse(OMI).unwrap(), &directory).unwrap();
+        assert_eq!(
+            list_files(&directory),
+            vec!["hl7_P300_0.json", "hl7_P300_1.json"]
+        );
+        let item: JsonValue =
+            serde_json::from_str(&cache::read(&directory.join("hl7_P300_0.json")).unwrap())
+                .unwrap();
+        assert_eq!(item["0040,0100"][0]["0040,0009"], "SPS3");
+
+        // A changed order with fewer steps replaces the previous files.
+        let changed = OMI.replace("IPC|ACC3|RP3|1.2.3|SPS4|MR||MR2|ROOM2|MRAET2\r", "");
+        apply_orders(&Message::parse(&changed).unwrap(), &directory).unwrap();
+        assert_eq!(list_files(&directory), vec!["hl7_P300_0.json"]);
+
+        // Files of other orders are kept.
+        apply_orders(&Message::parse(ORM).unwrap(), &directory).unwrap();
+        let canceled = OMI.replace("ORC|NW|", "ORC|CA|");
+        apply_orders(&Message::parse(&canceled).unwrap(), &directory).unwrap();
+        assert_eq!(list_files(&directory), vec!["hl7_P100_0.json"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_orders_without_order_number() {
+        let directory = test_directory("no_order_number");
+        let message = Message::parse(
+            &ORM.replace("ORC|NW|P100|F200", "ORC|NW")
+                .replace("OBR|1|P100|F200|", "OBR|1|||"),
+        )
+        .unwrap();
+        assert!(apply_orders(&message, &directory).is_err());
+        assert!(list_files(&directory).is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn builds_acks() {
+        let message = Message::parse(ORM).unwrap();
+        let ack = ack(Some(&message), "AE", "Disk full | retry");
+        let segments: Vec<&str> = ack.split('\r').collect();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[2], "");
+
+        let msh: Vec<&str> = segments[0].split('|').collect();
+        assert_eq!(&msh[..5], &["MSH", "^~\\&", "VARA", "BUFFER", "RIS"]);
+        assert_eq!(msh[5], "HOSP");
+        assert_eq!(msh[6].len(), 14);
+        assert_eq!(msh[8], "ACK^O01^ACK");
+        assert_eq!(&msh[10..], &["P", "2.3"]);
+        assert_eq!(segments[1], "MSA|AE|MSG0001|Disk full \\F\\ retry");
+
+        let ack = super::ack(None, "AR", "Invalid");
+        assert!(ack.contains("|ACK^^ACK|"));
+        assert!(ack.ends_with("|P|2.5\rMSA|AR||Invalid\r"));
+    }
+
+    #[test]
+    fn reads_mllp_frames() {
+        let mut reader = Cursor::new(b"\x0bMSG1\x1c\r\x0bMSG2\x1c\r".to_vec());
+        assert_eq!(
+            read_mllp_frame(&mut reader).unwrap(),
+            Some(b"MSG1".to_vec())
+        );
+        assert_eq!(
+            read_mllp_frame(&mut reader).unwrap(),
+            Some(b"MSG2".to_vec())
+        );
+        assert_eq!(read_mllp_frame(&mut reader).unwrap(), None);
+
+        let mut reader = Cursor::new(b"\x0bMSG".to_vec());
+        assert_eq!(
+            read_mllp_frame(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_mllp_frames() {
+        let mut frame = vec![MLLP_START_BLOCK];
+        frame.extend(vec![b'A'; MAX_MLLP_FRAME_SIZE as usize + 10]);
+        frame.extend([MLLP_END_BLOCK, MLLP_CARRIAGE_RETURN]);
+        let mut reader = Cursor::new(frame);
+        assert_eq!(
+            read_mllp_frame(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut reader = Cursor::new(vec![b'A'; MAX_MLLP_FRAME_SIZE as usize + 10]);
+        assert_eq!(
+            read_mllp_frame(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn decodes_with_the_declared_charset() {
+        let header = "MSH|^~\\&|RIS|HOSP|VARA|BUFFER|20231018120000||ORM^O01|MSG0001|P|2.3|||||";
+        let utf8 = format!("{}|UNICODE UTF-8\rPID|1||1||MÜLLER^JÜRGEN\r", header);
+        assert_eq!(decode_message(utf8.as_bytes()), utf8);
+
+        let mut latin1 = format!("{}|8859/1~UNICODE UTF-8\rPID|1||1||M", header).into_bytes();
+        latin1.extend(b"\xdcLLER\r");
+        assert!(decode_message(&latin1).ends_with("PID|1||1||MÜLLER\r"));
+
+        let mut greek = format!("{}|8859/7\rPID|1||1||", header).into_bytes();
+        greek.extend(b"\xc1\r");
+        assert!(decode_message(&greek).ends_with("||Α\r"));
+    }
+
+    #[test]
+    fn decodes_as_latin1_by_default() {
+        let mut message = b"MSH|^~\\&|RIS|HOSP|VARA|BUFFER|20231018120000||ORM^O01|MSG0001|P|2.3\r\
+            PID|1||1||M"
+            .to_vec();
+        message.extend(b"\xdcLLER\r");
+        assert!(decode_message(&message).ends_with("||MÜLLER\r"));
+
+        let header = "MSH|^~\\&|RIS|HOSP|VARA|BUFFER|20231018120000||ORM^O01|MSG0001|P|2.3|||||";
+        let mut unknown = format!("{}|ASCII\rPID|1||1||M", header).into_bytes();
+        unknown.extend(b"\xe9\r");
+        assert!(decode_message(&unknown).ends_with("||Mé\r"));
+        assert_eq!(
+            get_declared_charset(format!("{}|\r", header).as_bytes()),
+            None
+        );
+        assert_eq!(
+            get_declared_charset(format!("\n{}| 8859/1 \r", header).as_bytes()),
+            Some(String::from("8859/1"))
+        );
+    }
+}
@@ -0,0 +1,245 @@
+// Selects the endpoints queried for a C-FIND worklist request, and the
+// answers returned, from the AE titles of the modality and of Orthanc. See
+// `WorklistRoutingRule`.
+
+use super::tag_value;
+use crate::orthanc::plugin;
+use crate::orthanc::plugin::WorklistEndpoint;
+use crate::orthanc::plugin::WorklistRoutingPolicy;
+use crate::orthanc::plugin::WorklistRoutingRule;
+
+use serde_json::Value as JsonValue;
+
+pub struct Route {
+    pub endpoints: Vec<WorklistEndpoint>,
+    rule: Option<WorklistRoutingRule>,
+}
+
+// Returns `None` if the request is denied.
+pub fn route(
+    calling_aet: &str,
+    called_aet: &str,
+    endpoints: Vec<WorklistEndpoint>,
+) -> Option<Route> {
+    select_route(
+        calling_aet,
+        called_aet,
+        endpoints,
+        plugin::get_worklist_routing_rules(),
+        plugin::get_worklist_routing_policy(),
+    )
+}
+
+fn select_route(
+    calling_aet: &str,
+    called_aet: &str,
+    endpoints: Vec<WorklistEndpoint>,
+    rules: Vec<WorklistRoutingRule>,
+    policy: WorklistRoutingPolicy,
+) -> Option<Route> {
+    // Modalities commonly pad AE titles with spaces.
+    let calling_aet = calling_aet.trim();
+    let called_aet = called_aet.trim();
+    let rule = rules.into_iter().find(|rule| {
+        rule.calling_aet.trim() == calling_aet
+            && rule.called_aet.iter().all(|aet| aet.trim() == called_aet)
+    });
+
+    let rule = match rule {
+        Some(rule) => rule,
+        None => {
+            return match policy {
+                WorklistRoutingPolicy::AllowAll => Some(Route {
+                    endpoints,
+                    rule: None,
+                }),
+                WorklistRoutingPolicy::Deny => None,
+            };
+        }
+    };
+
+    let endpoints = match &rule.endpoints {
+        None => endpoints,
+        Some(names) => {
+            for name in names {
+                if !endpoints.iter().any(|endpoint| &endpoint.name == name) {
+                    plugin::warning(&format!(
+                        "Unknown worklist endpoint {} in routing rule for {}",
+                        name, rule.calling_aet
+                    ));
+                }
+            }
+            endpoints
+                .into_iter()
+                .filter(|endpoint| names.contains(&endpoint.name))
+                .collect()
+        }
+    };
+    Some(Route {
+        endpoints,
+        rule: Some(rule),
+    })
+}
+
+impl Route {
+    // Whether a worklist item has a scheduled procedure step matching the
+    // ScheduledStationAETitle and Modality filters of the rule.
+    pub fn accepts(&self, item: &JsonValue) -> bool {
+        let rule = match &self.rule {
+            Some(rule) if !rule.scheduled_station_ae_titles.is_empty() => rule,
+            Some(rule) if !rule.modalities.is_empty() => rule,
+            _ => return true,
+        };
+        let matches = |allowed: &[String], value: Option<&str>| {
+            allowed.is_empty()
+                || value.is_some_and(|value| {
+                    allowed.iter().any(|allowed| allowed.trim() == value.trim())
+                })
+        };
+
+        [
+            item.get("0040,0100"),
+            item.get("ScheduledProcedureStepSequence"),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(JsonValue::as_array)
+        .flatten()
+        .any(|step| {
+            matches(
+                &rule.scheduled_station_ae_titles,
+                tag_value(step, "0040,0001", "ScheduledStationAETitle"),
+            ) && matches(&rule.modalities, tag_value(step, "0008,0060", "Modality"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn endpoints() -> Vec<WorklistEndpoint> {
+        serde_json::from_value(json!([
+            {"Name": "ris", "Url": "http://ris"},
+            {"Name": "fhir", "Type": "FHIR", "Url": "http://fhir"}
+        ]))
+        .unwrap()
+    }
+
+    fn rules() -> Vec<WorklistRoutingRule> {
+        serde_json::from_value(json!([
+            {"CallingAet": "CT1", "CalledAet": "VARA", "Endpoints": ["fhir"],
+             "Modalities": ["CT"]},
+            {"CallingAet": "CT1", "ScheduledStationAETitles": ["CT1"]},
+            {"CallingAet": "US1"}
+        ]))
+        .unwrap()
+    }
+
+    fn names(route: &Route) -> Vec<&str> {
+        route
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn selects_the_first_matching_rule() {
+        let route = select_route(
+            " CT1 ",
+            "VARA  ",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::Deny,
+        )
+        .unwrap();
+        assert_eq!(names(&route), vec!["fhir"]);
+        assert_eq!(route.rule.unwrap().modalities, vec!["CT"]);
+
+        // The called AE title of the first rule does not match.
+        let route = select_route(
+            "CT1",
+            "OTHER",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::Deny,
+        )
+        .unwrap();
+        assert_eq!(names(&route), vec!["ris", "fhir"]);
+        assert_eq!(route.rule.unwrap().scheduled_station_ae_titles, vec!["CT1"]);
+    }
+
+    #[test]
+    fn applies_the_default_policy_without_a_matching_rule() {
+        let route = select_route(
+            "MR1",
+            "VARA",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::AllowAll,
+        )
+        .unwrap();
+        assert_eq!(names(&route), vec!["ris", "fhir"]);
+        assert!(route.rule.is_none());
+        assert!(select_route(
+            "MR1",
+            "VARA",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::Deny
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn filters_items_by_step() {
+        let route = select_route(
+            "CT1",
+            "VARA",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::Deny,
+        )
+        .unwrap();
+        assert!(route.accepts(&json!({"0040,0100": [{"0008,0060": "MR"}, {"0008,0060": "CT"}]})));
+        assert!(route.accepts(&json!({"ScheduledProcedureStepSequence": [{"Modality": " CT "}]})));
+        assert!(!route.accepts(&json!({"0040,0100": [{"0008,0060": "MR"}]})));
+        assert!(!route.accepts(&json!({"0040,0100": [{}]})));
+        assert!(!route.accepts(&json!({})));
+
+        let route = select_route(
+            "CT1",
+            "OTHER",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::Deny,
+        )
+        .unwrap();
+        assert!(route.accepts(&json!({"0040,0100": [{"0040,0001": "CT1"}]})));
+        assert!(!route.accepts(&json!({"0040,0100": [{"0040,0001": "CT2"}]})));
+    }
+
+    #[test]
+    fn accepts_every_item_without_filters() {
+        let route = select_route(
+            "US1",
+            "VARA",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::Deny,
+        )
+        .unwrap();
+        assert!(route.accepts(&json!({})));
+        let route = select_route(
+            "MR1",
+            "VARA",
+            endpoints(),
+            rules(),
+            WorklistRoutingPolicy::AllowAll,
+        )
+        .unwrap();
+        assert!(route.accepts(&json!({"0040,0100": [{"0008,0060": "MR"}]})));
+    }
+}
@@ -0,0 +1,270 @@
+// Worklist source querying the workitems of a DICOMweb UPS-RS service
+// (`GET {Url}/workitems`). The keys of the C-FIND query are translated into
+// QIDO-style query parameters, and the returned workitems (DICOM JSON, see
+// PS3.18 Annex F) are translated into worklist items.
+//
+// UPS workitems and MWL items do not share the same structure, e.g. the
+// requested procedure of a workitem is in its ReferencedRequestSequence and its
+// scheduled start is a single DateTime instead of a Date and a Time in the
+// ScheduledProcedureStepSequence.
+
+use crate::dicom_json;
+use crate::orthanc::plugin;
+use crate::orthanc::plugin::WorklistEndpoint;
+
+use reqwest::header::ACCEPT;
+use reqwest::StatusCode;
+use serde_json::json;
+use serde_json::Map as JsonMap;
+use serde_json::Value as JsonValue;
+use std::error::Error;
+
+pub fn fetch_worklist(
+    endpoint: &WorklistEndpoint,
+    worklist_query: &JsonValue,
+) -> Result<Vec<JsonValue>, Box<dyn Error>> {
+    let http_client = plugin::get_http_client();
+    let request = http_client
+        .get(format!("{}/workitems", endpoint.url.trim_end_matches('/')))
+        .query(&query_parameters(worklist_query))
+        .header(ACCEPT, "application/dicom+json");
+    let response = super::authorize(endpoint, request)
+        .send()?
+        .error_for_status()?;
+
+    // 204 (No Content) is returned when no workitem matches.
+    if response.status() == StatusCode::NO_CONTENT {
+        return Ok(vec![]);
+    }
+    let workitems: Vec<JsonValue> = response.json()?;
+    workitems
+        .iter()
+        .map(|workitem| Ok(worklist_item(&dicom_json::from_dicomweb(workitem)?)))
+        .collect()
+}
+
+// Builds the query parameters of the search from the C-FIND query (with
+// human-readable tag names, see `worklist_query_json`). Only keys with a
+// value are used for matching, answers are matched against the full query
+// with `dicom_matches_query` anyway.
+fn query_parameters(worklist_query: &JsonValue) -> Vec<(String, String)> {
+    let mut parameters = vec![(
+        String::from("ProcedureStepState"),
+        String::from("SCHEDULED"),
+    )];
+    let mut add = |attribute: &str, value: &JsonValue| {
+        if let Some(value) = value.as_str() {
+            if !value.is_empty() && value != "*" {
+                parameters.push((attribute.to_string(), value.to_string()));
+            }
+        }
+    };
+
+    for key in ["PatientID", "PatientName", "PatientBirthDate", "PatientSex"] {
+        add(key, &worklist_query[key]);
+    }
+    for key in [
+        "AccessionNumber",
+        "RequestedProcedureID",
+        "StudyInstanceUID",
+    ] {
+        add(
+            &format!("ReferencedRequestSequence.{}", key),
+            &worklist_query[key],
+        );
+    }
+
+    let step = &worklist_query["ScheduledProcedureStepSequence"][0];
+    add(
+        "ScheduledStationClassCodeSequence.CodeValue",
+        &step["Modality"],
+    );
+    add(
+        "ScheduledStationNameCodeSequence.CodeValue",
+        &step["ScheduledStationAETitle"],
+    );
+    if let Some(date) = step["ScheduledProcedureStepStartDate"].as_str() {
+        add(
+            "ScheduledProcedureStepStartDateTime",
+            &json!(date_range_to_datetime_range(date)),
+        );
+    }
+    parameters
+}
+
+// `20231018` -> `20231018000000-20231018235959`, `20231018-` ->
+// `20231018000000-`, etc.
+fn date_range_to_datetime_range(date_range: &str) -> String {
+    match date_range.split_once('-') {
+        None if date_range.is_empty() => String::new(),
+        None => format!("{}000000-{}235959", date_range, date_range),
+        Some((from, to)) => format!(
+            "{}-{}",
+            if from.is_empty() {
+                String::new()
+            } else {
+                format!("{}000000", from)
+            },
+            if to.is_empty() {
+                String::new()
+            } else {
+                format!("{}235959", to)
+            },
+        ),
+    }
+}
+
+// Maps a workitem (with Orthanc's `"0010,0010"` keys) to a worklist item.
+fn worklist_item(workitem: &JsonValue) -> JsonValue {
+    let string = |value: &JsonValue| value.as_str().unwrap_or_default().to_string();
+    let request = &workitem["0040,A370"][0];
+    let station_name = &workitem["0040,4025"][0];
+    let start_datetime = string(&workitem["0040,4005"]);
+    let sop_instance_uid = string(&workitem["0008,0018"]);
+    // ScheduledProcedureStepID is limited to 16 characters, the end of the
+    // workitem UID is the part most likely to be unique.
+    let step_id: String = {
+        let chars: Vec<char> = sop_instance_uid.chars().collect();
+        chars[chars.len().saturating_sub(16)..].iter().collect()
+    };
+
+    let mut item = JsonMap::new();
+    item.insert("0008,0005".into(), json!("ISO_IR 192"));
+    for tag in [
+        "0010,0010",
+        "0010,0020",
+        "0010,0030",
+        "0010,0040",
+        "0038,0010",
+    ] {
+        item.insert(tag.into(), json!(string(&workitem[tag])));
+    }
+    for tag in ["0008,0050", "0032,1032", "0032,1060", "0040,1001"] {
+        item.insert(tag.into(), json!(string(&request[tag])));
+    }
+    if let Some(study_instance_uid) = request["0020,000D"].as_str().filter(|uid| !uid.is_empty()) {
+        item.insert("0020,000D".into(), json!(study_instance_uid));
+    }
+    if request["0032,1064"].is_array() {
+        item.insert("0032,1064".into(), request["0032,1064"].clone());
+    }
+    item.insert(
+        "0040,0100".into(),
+        json!([{
+            "0008,0060": string(&workitem["0040,4026"][0]["0008,0100"]),
+            "0040,0001": string(&station_name["0008,0100"]),
+            "0040,0002": start_datetime.chars().take(8).collect::<String>(),
+            "0040,0003": start_datetime.chars().skip(8).take(6).collect::<String>(),
+            "0040,0006": string(&workitem["0040,4034"][0]["0040,4037"]),
+            "0040,0007": string(&workitem["0074,1204"]),
+            "0040,0009": step_id,
+            "0040,0010": string(&station_name["0008,0104"]),
+        }]),
+    );
+    JsonValue::Object(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_date_ranges() {
+        assert_eq!(
+            date_range_to_datetime_range("20231018"),
+            "20231018000000-20231018235959"
+        );
+        assert_eq!(
+            date_range_to_datetime_range("20231018-20231020"),
+            "20231018000000-20231020235959"
+        );
+        assert_eq!(date_range_to_datetime_range("20231018-"), "20231018000000-");
+        assert_eq!(date_range_to_datetime_range("-20231020"), "-20231020235959");
+        assert_eq!(date_range_to_datetime_range(""), "");
+    }
+
+    #[test]
+    fn builds_query_parameters() {
+        let query = json!({
+            "PatientID": "12345",
+            "PatientName": "DOE^*",
+            "PatientSex": "",
+            "AccessionNumber": "ACC1",
+            "StudyInstanceUID": "*",
+            "ScheduledProcedureStepSequence": [{
+                "Modality": "CT",
+                "ScheduledStationAETitle": "CT1",
+                "ScheduledProcedureStepStartDate": "20231018-"
+            }]
+        });
+        let parameter = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            query_parameters(&query),
+            vec![
+                parameter("ProcedureStepState", "SCHEDULED"),
+                parameter("PatientID", "12345"),
+                parameter("PatientName", "DOE^*"),
+                parameter("ReferencedRequestSequence.AccessionNumber", "ACC1"),
+                parameter("ScheduledStationClassCodeSequence.CodeValue", "CT"),
+                parameter("ScheduledStationNameCodeSequence.CodeValue", "CT1"),
+                parameter("ScheduledProcedureStepStartDateTime", "20231018000000-"),
+            ]
+        );
+        assert_eq!(
+            query_parameters(&json!({})),
+            vec![parameter("ProcedureStepState", "SCHEDULED")]
+        );
+    }
+
+    #[test]
+    fn maps_workitems() {
+        let workitem = json!({
+            "0008,0018": "1.2.826.0.1.3680043.8.498.12345678901234567890",
+            "0010,0010": "DOE^JOHN",
+            "0010,0020": "12345",
+            "0010,0030": "19800101",
+            "0040,A370": [{
+                "0008,0050": "ACC1",
+                "0020,000D": "1.2.3",
+                "0032,1060": "CT Head",
+                "0040,1001": "RP1",
+                "0032,1064": [{"0008,0100": "CTHEAD"}]
+            }],
+            "0040,4005": "20231018143000",
+            "0040,4025": [{"0008,0100": "CT1", "0008,0104": "Scanner 1"}],
+            "0040,4026": [{"0008,0100": "CT"}],
+            "0074,1204": "Head CT"
+        });
+        let item = worklist_item(&workitem);
+        assert_eq!(item["0008,0005"], "ISO_IR 192");
+        assert_eq!(item["0010,0010"], "DOE^JOHN");
+        assert_eq!(item["0010,0040"], "");
+        assert_eq!(item["0008,0050"], "ACC1");
+        assert_eq!(item["0020,000D"], "1.2.3");
+        assert_eq!(item["0032,1060"], "CT Head");
+        assert_eq!(item["0040,1001"], "RP1");
+        assert_eq!(item["0032,1064"][0]["0008,0100"], "CTHEAD");
+        assert_eq!(
+            item["0040,0100"],
+            json!([{
+                "0008,0060": "CT",
+                "0040,0001": "CT1",
+                "0040,0002": "20231018",
+                "0040,0003": "143000",
+                "0040,0006": "",
+                "0040,0007": "Head CT",
+                "0040,0009": "5678901234567890",
+                "0040,0010": "Scanner 1",
+            }])
+        );
+    }
+
+    #[test]
+    fn maps_incomplete_workitems() {
+        let item = worklist_item(&json!({"0008,0018": "1.2.3"}));
+        assert!(item.get("0020,000D").is_none());
+        assert!(item.get("0032,1064").is_none());
+        assert_eq!(item["0040,0100"][0]["0040,0009"], "1.2.3");
+        assert_eq!(item["0040,0100"][0]["0040,0002"], "");
+    }
+}